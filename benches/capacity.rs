@@ -0,0 +1,93 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use matchbox::{Command, CommandAtTime, Engine, EngineConfig, Place, Side, TimeInForce};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+/*
+    A burst of resting orders into an engine made with Engine::new and one
+    made with Engine::with_capacity, counting the allocations each makes
+    along the way. Run with `cargo bench --bench capacity`.
+*/
+const ORDERS: u64 = 100_000;
+const ACCOUNTS: u64 = 1_000;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// bids below 1000 and asks above, so nothing trades and every order rests
+fn order(i: u64) -> CommandAtTime {
+    let (side, price) = match i % 2 {
+        0 => (Side::Buy, 1000 - (i % 50) as i64),
+        _ => (Side::Sell, 1001 + (i % 50) as i64),
+    };
+    CommandAtTime::new(
+        i,
+        Command::Place(Place::LimitOrder {
+            uuid: Uuid::from_u128(i as u128 + 1),
+            account: i % ACCOUNTS,
+            side,
+            amount: Decimal::ONE,
+            tif: TimeInForce::GTC,
+            price: Decimal::from(price),
+            post_only: false,
+            min_qty: None,
+            reduce_only: false,
+            aon: false,
+            cancel_on_disconnect: false,
+            max_price_range: None,
+        }),
+    )
+}
+
+fn burst(name: &str, mut engine: Engine) {
+    let commands: Vec<CommandAtTime> = (0..ORDERS).map(order).collect();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for command in commands {
+        black_box(engine.call(command).unwrap());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<14} {:>9} allocations {:>11} bytes {:>8.1} ns/order",
+        name,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        BYTES.load(Ordering::Relaxed) - bytes,
+        elapsed.as_nanos() as f64 / ORDERS as f64
+    );
+}
+
+fn main() {
+    burst("new", Engine::new());
+    burst(
+        "with_capacity",
+        Engine::with_capacity(EngineConfig::default(), ORDERS as usize, ACCOUNTS as usize),
+    );
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeSet;
+use std::hint::black_box;
+use std::mem;
+use std::time::Instant;
+
+use matchbox::{Command, CommandAtTime, Engine, Place, Side, TimeInForce};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+/*
+    Merging what a flush expired into a command's closed orders, the way
+    merge used to (a union collected into a new set) against the way it
+    does now (the smaller set inserted into the larger), and then a replay
+    in which every command has something to merge. Run with
+    `cargo bench --bench merge`.
+*/
+const ROUNDS: usize = 200_000;
+
+fn uuids(from: u128, n: u128) -> BTreeSet<Uuid> {
+    (from..from + n).map(Uuid::from_u128).collect()
+}
+
+fn union(closed: BTreeSet<Uuid>, expired: BTreeSet<Uuid>) -> BTreeSet<Uuid> {
+    closed.union(&expired).copied().collect()
+}
+
+fn in_place(mut closed: BTreeSet<Uuid>, mut expired: BTreeSet<Uuid>) -> BTreeSet<Uuid> {
+    if expired.len() > closed.len() {
+        mem::swap(&mut closed, &mut expired);
+    }
+    closed.extend(expired);
+    closed
+}
+
+fn time(name: &str, merge: fn(BTreeSet<Uuid>, BTreeSet<Uuid>) -> BTreeSet<Uuid>) {
+    for (closed, expired) in [(1, 1), (2, 0), (8, 2), (64, 4)] {
+        let inputs: Vec<(BTreeSet<Uuid>, BTreeSet<Uuid>)> = (0..ROUNDS)
+            .map(|_| (uuids(0, closed), uuids(closed, expired)))
+            .collect();
+        let start = Instant::now();
+        for (closed, expired) in inputs {
+            black_box(merge(closed, expired));
+        }
+        println!(
+            "{:<8} {:>2} closed + {} expired {:>8.1} ns/merge",
+            name,
+            closed,
+            expired,
+            start.elapsed().as_nanos() as f64 / ROUNDS as f64
+        );
+    }
+}
+
+// a GTD order each tick, expiring on the next, so every call merges an expiry
+fn replay() {
+    let mut engine = Engine::new();
+    let commands: Vec<CommandAtTime> = (0..ROUNDS as u64)
+        .map(|i| {
+            CommandAtTime::new(
+                i + 1,
+                Command::Place(Place::LimitOrder {
+                    uuid: Uuid::from_u128(i as u128 + 1),
+                    account: 1,
+                    side: Side::Buy,
+                    amount: Decimal::ONE,
+                    tif: TimeInForce::GTD(1),
+                    price: Decimal::from(100 + (i % 10) as i64),
+                    post_only: false,
+                    min_qty: None,
+                    reduce_only: false,
+                    aon: false,
+                    cancel_on_disconnect: false,
+                    max_price_range: None,
+                }),
+            )
+        })
+        .collect();
+    let start = Instant::now();
+    for command in commands {
+        black_box(engine.call(command).unwrap());
+    }
+    println!(
+        "replay, one expiry per call {:>8.1} ns/call",
+        start.elapsed().as_nanos() as f64 / ROUNDS as f64
+    );
+}
+
+fn main() {
+    time("union", union);
+    time("in place", in_place);
+    replay();
+}
@@ -0,0 +1,98 @@
+use std::io;
+use std::io::{BufRead, Write};
+
+use crate::{CommandAtTime, Engine, Number};
+
+/*
+    An append-only record of every command an Engine has applied, one JSON
+    object per line. The engine is deterministic, so replaying the log into
+    an engine configured the same way rebuilds exactly the same state.
+*/
+pub struct CommandLog {
+    writer: Box<dyn Write + Send>,
+}
+
+impl CommandLog {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> CommandLog {
+        CommandLog {
+            writer: Box::new(writer),
+        }
+    }
+
+    pub(crate) fn record<N: Number>(
+        &mut self,
+        command_at_time: &CommandAtTime<N>,
+    ) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, command_at_time)?;
+        self.writer.write_all(b"\n")?;
+        // it's only a write-ahead log if it's written before we go ahead
+        self.writer.flush()
+    }
+}
+
+/*
+    Apply every command in `log` to `engine`, which should be configured
+    the same way as the engine that wrote the log
+*/
+pub fn replay<N: Number, R: BufRead>(mut engine: Engine<N>, log: R) -> io::Result<Engine<N>> {
+    for line in log.lines() {
+        let command_at_time: CommandAtTime<N> = serde_json::from_str(&line?)?;
+        if let Err(e) = engine.call(command_at_time) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        }
+    }
+    Ok(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_line;
+    use std::sync::{Arc, Mutex};
+
+    // a log writer whose bytes the test can still read
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replaying_the_log_rebuilds_the_same_engine() {
+        let lines = [
+            "1,limit,00000000-0000-0000-0000-000000000001,1,sell,1,101,GTC",
+            "1,iceberg,00000000-0000-0000-0000-000000000002,2,sell,5,102,2,GTD,4",
+            "2,limit,00000000-0000-0000-0000-000000000003,3,buy,3,99,GTC",
+            "3,market,00000000-0000-0000-0000-000000000004,4,buy,2",
+            "3,amend,00000000-0000-0000-0000-000000000003,2,100",
+            // refused as out of order, and so not logged
+            "2,cancel,00000000-0000-0000-0000-000000000003",
+            "6,limit,00000000-0000-0000-0000-000000000005,5,sell,1,100,GTC",
+        ];
+        let log = Shared::default();
+        let mut engine: Engine = Engine::new();
+        engine.set_command_log(CommandLog::new(log.clone()));
+        for line in lines {
+            let _ = engine.call(parse_line(line.to_string()).unwrap());
+        }
+
+        let bytes = log.0.lock().unwrap().clone();
+        assert_eq!(
+            bytes.iter().filter(|&&b| b == b'\n').count(),
+            lines.len() - 1
+        );
+        let replayed = replay(Engine::new(), &bytes[..]).unwrap();
+        assert_eq!(replayed.check_consistency(), Ok(()));
+        assert_eq!(
+            serde_json::to_string(&replayed.snapshot()).unwrap(),
+            serde_json::to_string(&engine.snapshot()).unwrap()
+        );
+    }
+}
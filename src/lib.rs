@@ -0,0 +1,6949 @@
+use std::fmt;
+use std::str::FromStr;
+
+use log::{debug, trace};
+use rust_decimal::prelude::*;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::mem;
+use uuid::Uuid;
+
+mod command_log;
+mod number;
+mod rng;
+mod router;
+mod snapshot;
+
+pub use command_log::{replay, CommandLog};
+pub use number::Number;
+use rng::Rng;
+pub use router::{parse_routed_line, EngineRouter, RoutedResult};
+pub use snapshot::EngineSnapshot;
+
+// time is in nanoseconds, whatever unit the input gives it in
+pub const NANOSECOND: u64 = 1;
+pub const MICROSECOND: u64 = 1000 * NANOSECOND;
+pub const MILLISECOND: u64 = 1000 * MICROSECOND;
+pub const SECOND: u64 = 1000 * MILLISECOND;
+pub const DAY: u64 = SECOND * 60 * 60 * 24;
+pub const MAX_LIFETIME: u64 = 90 * DAY;
+// expiry of an order that never expires
+pub const NEVER: u64 = u64::MAX;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+fn other_side(side: Side) -> Side {
+    match side {
+        Side::Buy => Side::Sell,
+        Side::Sell => Side::Buy,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SideParseError(());
+
+impl FromStr for Side {
+    type Err = SideParseError;
+
+    // case-insensitive, and takes the aliases feeds commonly use
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let any_of = |names: [&str; 3]| names.iter().any(|name| s.eq_ignore_ascii_case(name));
+        if any_of(["buy", "b", "bid"]) {
+            Ok(Side::Buy)
+        } else if any_of(["sell", "s", "ask"]) {
+            Ok(Side::Sell)
+        } else {
+            Err(SideParseError(()))
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Side::Buy => write!(f, "buy"),
+            Side::Sell => write!(f, "sell"),
+        }
+    }
+}
+
+// the unit of the times in an input line; they are scaled to nanoseconds as they are parsed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimeUnit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+}
+
+impl TimeUnit {
+    pub fn nanos(self) -> u64 {
+        match self {
+            TimeUnit::Nanoseconds => NANOSECOND,
+            TimeUnit::Microseconds => MICROSECOND,
+            TimeUnit::Milliseconds => MILLISECOND,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    GTC,
+    IOC,
+    FOK,
+    GTD(u64), // lifetime in nanoseconds
+    GTT(u64), // absolute expiry time in nanoseconds
+}
+
+// the price a pegged order follows
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PegRef {
+    BestBid,
+    BestAsk,
+    Mid,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+struct Peg<N> {
+    reference: PegRef,
+    offset: N,
+}
+/*
+
+    Not sure yet how to specify (im)mutability in
+    nested structs in rust.
+
+    I'd like to make Order mostly immutable, apart from `remaining_amount`,
+    but it has to live inside a BTreeMap which is obviously mutable.
+*/
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub struct Order<N = Decimal> {
+    uuid: Uuid,
+    account: u64,
+    side: Side,
+    created: u64,
+    amount: N,
+    price: Option<N>, // None for market orders, which never rest
+    tif: TimeInForce,
+    post_only: bool,
+    display_size: Option<N>, // only set for icebergs
+    #[serde(default)]
+    display_jitter: Option<N>,
+    #[serde(default)]
+    min_qty: Option<N>,
+    #[serde(default)]
+    reduce_only: bool,
+    #[serde(default)]
+    aon: bool,
+    #[serde(default)]
+    peg: Option<Peg<N>>,
+    #[serde(default)]
+    cancel_on_disconnect: bool,
+    #[serde(default)]
+    max_price_range: Option<N>,
+    // placed without a limit, so any price it has was set by the engine
+    #[serde(default)]
+    market: bool,
+    //This is the only field that needs to be mutable; maybe
+    // we should use Cell<Decimal> ??
+    remaining_amount: N,
+    // iceberg reserve, not part of remaining_amount and not visible
+    hidden_amount: N,
+}
+/*
+    A read-only copy of a resting order
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrderView<N = Decimal> {
+    pub uuid: Uuid,
+    pub account: u64,
+    pub side: Side,
+    pub price: N,
+    pub amount: N,
+    pub remaining_amount: N,
+    pub hidden_amount: N,
+    pub created: u64,
+    pub tif: TimeInForce,
+}
+
+/*
+    An order removed by flush because its time in force ran out
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(bound = "N: Number")]
+pub struct ExpiredOrder<N = Decimal> {
+    pub uuid: Uuid,
+    pub side: Side,
+    pub price: N,
+    pub remaining_amount: N, // including any hidden iceberg reserve
+    pub sequence: u64,
+}
+
+/*
+    The visible size now at one price level that a call changed, when the
+    engine reports book deltas. `size` is zero for a level that is gone.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(bound = "N: Number")]
+pub struct LevelDelta<N = Decimal> {
+    pub side: Side,
+    pub price: N,
+    pub size: N,
+    pub change: LevelChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelChange {
+    Added,
+    Changed,
+    Removed,
+}
+
+impl fmt::Display for LevelChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelChange::Added => write!(f, "added"),
+            LevelChange::Changed => write!(f, "changed"),
+            LevelChange::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/*
+    The top of the book as `Engine::peek` finds it: (price, visible size)
+    of the best levels on each side, best first
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(bound = "N: Number")]
+pub struct BookView<N = Decimal> {
+    pub best_bid: Option<(N, N)>,
+    pub best_ask: Option<(N, N)>,
+    pub spread: Option<N>,
+    pub bids: Vec<(N, N)>,
+    pub asks: Vec<(N, N)>,
+}
+
+/*
+    Running totals over every fill since the engine started
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EngineStats<N = Decimal> {
+    pub fills: u64,
+    pub base_volume: N,
+    pub quote_volume: N,
+    pub last_trade_price: Option<N>,
+    pub resting_orders: usize, // on both sides, not counting waiting stops
+}
+
+/*
+    An account's net base holding from its fills, positive when long, and
+    the average price paid for it. Reducing a position leaves the average
+    as it was; going through zero starts it again at the fill price.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub struct Position<N = Decimal> {
+    pub net: N,
+    pub average_price: N,
+}
+
+impl<N: Number> Position<N> {
+    // `bought` is negative for a sale
+    fn apply(&mut self, bought: N, price: N) {
+        let net = self.net + bought;
+        if net.is_zero() {
+            self.average_price = N::ZERO;
+        } else if (net > N::ZERO) != (self.net > N::ZERO) || self.net.is_zero() {
+            self.average_price = price;
+        } else if net.abs() > self.net.abs() {
+            self.average_price = (self.net * self.average_price + bought * price) / net;
+        }
+        self.net = net;
+    }
+}
+
+// serialized by hand below; quote_amount is ignored when read back
+#[derive(Debug, Clone, Deserialize)]
+#[serde(bound = "N: Number")]
+pub struct Fill<N = Decimal> {
+    pub base_amount: N,
+    pub price: N,
+    pub maker_uuid: Uuid,
+    pub taker_uuid: Uuid,
+    pub maker_account: u64,
+    pub taker_account: u64,
+    pub taker_side: Side, // the aggressor; the maker is on the other side
+    // a negative maker fee is a rebate
+    pub maker_fee: N,
+    pub taker_fee: N,
+    pub sequence: u64,
+    // quote_amount rounded to the engine's quote precision, if it has one
+    pub settlement_amount: N,
+    // how far inside its limit the taker traded, when the engine reports it
+    #[serde(default)]
+    pub price_improvement: Option<N>,
+}
+
+/*
+    A run of fills by one taker at one price, printed as a single trade.
+    `fills` keeps each maker's part for settlement.
+*/
+#[derive(Debug, Clone, Serialize)]
+#[serde(bound = "N: Number")]
+pub struct Print<N = Decimal> {
+    pub taker_uuid: Uuid,
+    pub taker_side: Side,
+    pub price: N,
+    pub base_amount: N,
+    pub fills: Vec<Fill<N>>,
+}
+
+/*
+    How a quote amount is rounded to a fixed precision. HalfEven is
+    banker's rounding (2.345 -> 2.34, 2.355 -> 2.36); HalfUp takes a
+    midpoint away from zero (2.345 -> 2.35).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuoteRounding {
+    HalfEven,
+    HalfUp,
+}
+
+impl<N: Number> Fill<N> {
+    pub fn quote_amount(&self) -> N {
+        self.base_amount * self.price
+    }
+
+    pub fn rounded_quote_amount(&self, precision: u32, rounding: QuoteRounding) -> N {
+        self.quote_amount().round_to(precision, rounding)
+    }
+
+    // the fill as seen by the maker and by the taker, in that order
+    pub fn legs(&self) -> (ExecutionLeg<N>, ExecutionLeg<N>) {
+        let leg = |uuid, account, side, fee| ExecutionLeg {
+            uuid,
+            account,
+            side,
+            base_amount: self.base_amount,
+            price: self.price,
+            quote_amount: self.quote_amount(),
+            fee,
+        };
+        (
+            leg(
+                self.maker_uuid,
+                self.maker_account,
+                other_side(self.taker_side),
+                self.maker_fee,
+            ),
+            leg(
+                self.taker_uuid,
+                self.taker_account,
+                self.taker_side,
+                self.taker_fee,
+            ),
+        )
+    }
+}
+
+// one participant's half of a fill
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub struct ExecutionLeg<N = Decimal> {
+    pub uuid: Uuid,
+    pub account: u64,
+    pub side: Side, // what this participant did: bought or sold base
+    pub base_amount: N,
+    pub price: N,
+    pub quote_amount: N,
+    pub fee: N,
+}
+
+impl<N: Number> Serialize for Fill<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut fill = serializer.serialize_struct("Fill", 13)?;
+        fill.serialize_field("sequence", &self.sequence)?;
+        fill.serialize_field("maker_uuid", &self.maker_uuid)?;
+        fill.serialize_field("taker_uuid", &self.taker_uuid)?;
+        fill.serialize_field("maker_account", &self.maker_account)?;
+        fill.serialize_field("taker_account", &self.taker_account)?;
+        fill.serialize_field("taker_side", &self.taker_side)?;
+        fill.serialize_field("base_amount", &self.base_amount)?;
+        fill.serialize_field("price", &self.price)?;
+        fill.serialize_field("quote_amount", &self.quote_amount())?;
+        fill.serialize_field("settlement_amount", &self.settlement_amount)?;
+        fill.serialize_field("maker_fee", &self.maker_fee)?;
+        fill.serialize_field("taker_fee", &self.taker_fee)?;
+        match self.price_improvement {
+            Some(improvement) => fill.serialize_field("price_improvement", &improvement)?,
+            None => fill.skip_field("price_improvement")?,
+        }
+        fill.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    PostOnly, // would have taken liquidity
+    InvalidAmount,
+    InvalidPrice,
+    DuplicateUuid,   // an order with this uuid is already resting
+    InvalidExpiry,   // a GTT expiry that isn't in the future
+    OffTick,         // price not a multiple of the engine's price tick
+    OffLot,          // amount not a multiple of the engine's lot size
+    Halted,          // the engine isn't accepting orders
+    Auction,         // only orders that can rest are taken during an auction
+    OutsideBand,     // priced too far from the last trade
+    NoPosition,      // reduce-only, but the account has nothing to reduce
+    NoReference,     // pegged to, or capped at, a price the book doesn't have
+    BookFull,        // its side already has max_resting_orders resting
+    UnknownOrder,    // refers to an order that isn't on the book
+    LifetimeTooLong, // a GTD lifetime over the engine's max_gtd_lifetime
+    ThinBook,        // a market order the book can't fill min_fill_ratio of
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::PostOnly => write!(f, "post_only"),
+            RejectReason::InvalidAmount => write!(f, "invalid_amount"),
+            RejectReason::InvalidPrice => write!(f, "invalid_price"),
+            RejectReason::DuplicateUuid => write!(f, "duplicate_uuid"),
+            RejectReason::InvalidExpiry => write!(f, "invalid_expiry"),
+            RejectReason::OffTick => write!(f, "off_tick"),
+            RejectReason::OffLot => write!(f, "off_lot"),
+            RejectReason::Halted => write!(f, "halted"),
+            RejectReason::Auction => write!(f, "auction"),
+            RejectReason::OutsideBand => write!(f, "outside_band"),
+            RejectReason::NoPosition => write!(f, "no_position"),
+            RejectReason::NoReference => write!(f, "no_reference"),
+            RejectReason::BookFull => write!(f, "book_full"),
+            RejectReason::UnknownOrder => write!(f, "unknown_order"),
+            RejectReason::LifetimeTooLong => write!(f, "lifetime_too_long"),
+            RejectReason::ThinBook => write!(f, "thin_book"),
+        }
+    }
+}
+
+// why an order in `MatchResult::closed` is finished with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    Filled,
+    CanceledByUser, // cancelled, or amended or reduced to nothing
+    IocCanceled,    // what an IOC, FOK or market order couldn't fill
+    Expired,
+    Rejected,
+    ReduceOnly,   // a resting reduce-only order left with nothing to reduce
+    Disconnected, // cancel-on-disconnect, and the account's heartbeats stopped
+    NoLiquidity,  // a market order that found the other side of the book empty
+}
+
+/*
+    Rejected commands never touch the book; they are reported in `rejected`,
+    and a rejected new order is also reported in `closed` unless that would
+    be mistaken for the resting order it duplicates.
+
+    Results returned by `Engine::call` carry event sequence numbers: every
+    expiry, fill and closure gets the next number, with no gaps, for the
+    life of the engine (including across snapshot/restore). A consumer that
+    sees a number skipped has missed an event.
+*/
+#[derive(Debug, Default, Serialize)]
+#[serde(bound = "N: Number")]
+pub struct MatchResult<N = Decimal> {
+    pub fills: Vec<Fill<N>>,
+    pub closed: BTreeMap<Uuid, CloseReason>,
+    pub rejected: BTreeMap<Uuid, RejectReason>,
+    // expiries are also in `closed`, but are numbered here rather than there
+    pub expired: Vec<ExpiredOrder<N>>,
+    // sequence number of each closure in `closed` other than an expiry
+    pub closed_sequence: BTreeMap<Uuid, u64>,
+    /*
+        what an IOC, FOK or market order had left when it was cancelled, or
+        any order pulled by a drain
+    */
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unfilled: BTreeMap<Uuid, N>,
+    // buys then sells, each by price; only filled in by `call`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub book_deltas: Vec<LevelDelta<N>>,
+    // the order things happened in, see `events`
+    #[serde(skip)]
+    steps: Vec<Step>,
+}
+
+/*
+    One thing that happened during a call. `MatchResult::events` lists them
+    in the order they happened, where the fields of `MatchResult` group them
+    by kind.
+*/
+#[derive(Debug, Clone, Serialize)]
+#[serde(bound = "N: Number")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<N = Decimal> {
+    Fill(Fill<N>),
+    /*
+        `unfilled` is given for an order cancelled with something left to
+        trade: by its time in force, for want of liquidity, at the edge of
+        its price range, or by a drain
+    */
+    Canceled { uuid: Uuid, unfilled: Option<N> },
+    Expired(ExpiredOrder<N>),
+    FullyFilled { uuid: Uuid },
+    Rejected { uuid: Uuid, reason: RejectReason },
+}
+
+// fills and expiries are kept in their own lists, so only their position is recorded
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Fill,
+    Expired,
+    Canceled(Uuid),
+    FullyFilled(Uuid),
+    Rejected(Uuid, RejectReason),
+}
+
+impl<N: Number> MatchResult<N> {
+    pub fn events(&self) -> Vec<Event<N>> {
+        let mut fills = self.fills.iter();
+        let mut expired = self.expired.iter();
+        self.steps
+            .iter()
+            .map(|step| match *step {
+                Step::Fill => Event::Fill(fills.next().expect("Event mismatch").clone()),
+                Step::Expired => Event::Expired(*expired.next().expect("Event mismatch")),
+                Step::Canceled(uuid) => Event::Canceled {
+                    uuid,
+                    unfilled: self.unfilled.get(&uuid).copied(),
+                },
+                Step::FullyFilled(uuid) => Event::FullyFilled { uuid },
+                Step::Rejected(uuid, reason) => Event::Rejected { uuid, reason },
+            })
+            .collect()
+    }
+
+    // what `closed` was before it had reasons: every closure but expiries
+    pub fn closed_uuids(&self) -> BTreeSet<Uuid> {
+        self.closed
+            .iter()
+            .filter(|(_, reason)| **reason != CloseReason::Expired)
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+
+    /*
+        Volume-weighted average price over the fills, sum(base * price) /
+        sum(base). The sums are exact; the division is rounded in its last
+        digit by Decimal (28 significant digits), and truncated towards zero
+        for an integer number type.
+    */
+    pub fn vwap(&self) -> Option<N> {
+        let base: N = self.fills.iter().map(|fill| fill.base_amount).sum();
+        if base.is_zero() {
+            return None;
+        }
+        let quote: N = self.fills.iter().map(Fill::quote_amount).sum();
+        Some(quote / base)
+    }
+
+    /*
+        The fills as a tape would show them: consecutive fills with the same
+        taker and price merged into one print, in fill order. `fills` is
+        left as it was.
+    */
+    pub fn aggregated(&self) -> Vec<Print<N>> {
+        let mut prints: Vec<Print<N>> = Vec::new();
+        for fill in &self.fills {
+            match prints.last_mut() {
+                Some(print) if print.taker_uuid == fill.taker_uuid && print.price == fill.price => {
+                    print.base_amount += fill.base_amount;
+                    print.fills.push(fill.clone());
+                }
+                _ => prints.push(Print {
+                    taker_uuid: fill.taker_uuid,
+                    taker_side: fill.taker_side,
+                    price: fill.price,
+                    base_amount: fill.base_amount,
+                    fills: vec![fill.clone()],
+                }),
+            }
+        }
+        prints
+    }
+
+    // `rejected` in the order the rejections happened
+    pub fn rejected_in_order(&self) -> impl Iterator<Item = (Uuid, RejectReason)> + '_ {
+        self.steps.iter().filter_map(|step| match *step {
+            Step::Rejected(uuid, reason) => Some((uuid, reason)),
+            _ => None,
+        })
+    }
+
+    // the uuids of `closed_uuids` in the order they were closed
+    fn closures(&self) -> Vec<Uuid> {
+        let mut seen = BTreeSet::new();
+        let closures: Vec<Uuid> = self
+            .steps
+            .iter()
+            .filter_map(|step| match *step {
+                Step::FullyFilled(uuid) | Step::Canceled(uuid) | Step::Rejected(uuid, _) => {
+                    Some(uuid)
+                }
+                Step::Fill | Step::Expired => None,
+            })
+            .filter(|uuid| {
+                matches!(self.closed.get(uuid), Some(reason) if *reason != CloseReason::Expired)
+            })
+            .filter(|uuid| seen.insert(*uuid))
+            .collect();
+        debug_assert!(
+            closures.len() == self.closed_uuids().len(),
+            "closure without an event"
+        );
+        closures
+    }
+
+    fn append(&mut self, mut other: MatchResult<N>) {
+        self.fills.append(&mut other.fills);
+        self.closed.append(&mut other.closed);
+        self.rejected.append(&mut other.rejected);
+        self.expired.append(&mut other.expired);
+        self.unfilled.append(&mut other.unfilled);
+        self.steps.append(&mut other.steps);
+    }
+
+    fn fill(&mut self, fill: Fill<N>) {
+        trace!(
+            "fill maker={} taker={} amount={} price={}",
+            fill.maker_uuid,
+            fill.taker_uuid,
+            fill.base_amount,
+            fill.price
+        );
+        self.fills.push(fill);
+        self.steps.push(Step::Fill);
+    }
+
+    fn filled(&mut self, uuid: Uuid) {
+        self.closed.insert(uuid, CloseReason::Filled);
+        self.steps.push(Step::FullyFilled(uuid));
+    }
+
+    // an order already closed, say by filling, isn't canceled as well
+    fn canceled(&mut self, uuid: Uuid, reason: CloseReason) -> bool {
+        if self.closed.contains_key(&uuid) {
+            return false;
+        }
+        self.closed.insert(uuid, reason);
+        self.steps.push(Step::Canceled(uuid));
+        true
+    }
+
+    // an order that couldn't trade any further, along with what it had left
+    fn unfilled(&mut self, order: &Order<N>) {
+        self.unfilled_as(order, CloseReason::IocCanceled)
+    }
+
+    fn unfilled_as(&mut self, order: &Order<N>, reason: CloseReason) {
+        if self.canceled(order.uuid, reason) {
+            self.unfilled.insert(order.uuid, order.total_remaining());
+        }
+    }
+
+    fn reject(&mut self, uuid: Uuid, reason: RejectReason) {
+        debug!("reject uuid={} reason={}", uuid, reason);
+        self.rejected.insert(uuid, reason);
+        self.steps.push(Step::Rejected(uuid, reason));
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub enum Place<N = Decimal> {
+    /*
+        With `max_slippage` a market order stops matching once the price
+        has moved that far from the price of its first fill. With
+        `notional` its amount is in quote currency, to be spent rather
+        than bought or sold; see Engine::notional_base.
+    */
+    MarketOrder {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        #[serde(default)]
+        max_slippage: Option<N>,
+        #[serde(default)]
+        reduce_only: bool,
+        #[serde(default)]
+        notional: bool,
+    },
+    /*
+        A market order that only trades at the midpoint or better, taken
+        between the best bid and ask that aren't pegged (as for PegRef::Mid)
+        when it is placed. It is rejected if one side of the book is empty.
+    */
+    MidMarketOrder {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+    },
+    LimitOrder {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        tif: TimeInForce,
+        price: N,
+        post_only: bool,
+        // trade nothing unless at least this much can be filled at once
+        #[serde(default)]
+        min_qty: Option<N>,
+        #[serde(default)]
+        reduce_only: bool,
+        /*
+            All-or-none only ever trades its whole remaining amount at once.
+            A taker that can't fill an AON maker completely passes over it
+            and carries on with the orders behind, so an AON order can be
+            traded through while keeping its place. An AON taker that can't
+            be filled completely doesn't trade: IOC is cancelled, anything
+            else rests until there is enough.
+        */
+        #[serde(default)]
+        aon: bool,
+        /*
+            Cancelled if the account's heartbeats stop, when the engine has a
+            heartbeat timeout; see Engine::set_heartbeat_timeout
+        */
+        #[serde(default)]
+        cancel_on_disconnect: bool,
+        /*
+            Stop matching at any price more than this away from the price of
+            the first fill, and cancel what is left rather than rest it
+        */
+        #[serde(default)]
+        max_price_range: Option<N>,
+    },
+    /*
+        Only `display_size` of an iceberg rests visibly; when that is used
+        up it is refilled from the rest, and the new slice goes to the back
+        of the queue at its price.
+
+        With a `display_jitter` fraction j, in [0, 1), each slice is drawn
+        from display_size * (1 +/- j) instead, if the engine has a jitter
+        seed. Without one the jitter is ignored and every slice is
+        display_size, so the default engine is always deterministic.
+    */
+    IcebergOrder {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        price: N,
+        tif: TimeInForce,
+        display_size: N,
+        #[serde(default)]
+        display_jitter: Option<N>,
+    },
+    /*
+        Stop orders wait off the book until the last trade price reaches
+        `stop_price` (at or above it for a buy, at or below for a sell), and
+        then enter as a market order, or for StopLimit a limit order
+    */
+    StopOrder {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        stop_price: N,
+    },
+    StopLimit {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        stop_price: N,
+        limit_price: N,
+        tif: TimeInForce,
+    },
+    /*
+        A pegged order rests at `offset` from the best bid, the best ask or
+        the midpoint between them, and is moved after every command to
+        follow that price. Only orders that aren't pegged set the price
+        followed, so pegs never chase each other. An order moved to a new
+        price goes to the back of the queue there, like any other order
+        joining the level; pegs moved in the same command keep their order
+        relative to each other, and a peg whose price hasn't changed keeps
+        its place. A move that crosses the book trades as the taker.
+
+        With nothing to follow on placement the order is rejected; once
+        resting it stays where it is until there is again. It also stays
+        put rather than move to a price the engine wouldn't accept, and
+        while the engine is halted or in an auction. Amending a pegged order
+        changes its amount; its price goes back to the peg.
+    */
+    PeggedOrder {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        reference: PegRef,
+        offset: N,
+        tif: TimeInForce,
+    },
+}
+
+impl<N: Number> Place<N> {
+    fn uuid(&self) -> Uuid {
+        match self {
+            Place::MarketOrder { uuid, .. }
+            | Place::MidMarketOrder { uuid, .. }
+            | Place::LimitOrder { uuid, .. }
+            | Place::IcebergOrder { uuid, .. }
+            | Place::StopOrder { uuid, .. }
+            | Place::StopLimit { uuid, .. }
+            | Place::PeggedOrder { uuid, .. } => *uuid,
+        }
+    }
+
+    fn tif_mut(&mut self) -> Option<&mut TimeInForce> {
+        match self {
+            Place::LimitOrder { tif, .. }
+            | Place::IcebergOrder { tif, .. }
+            | Place::StopLimit { tif, .. }
+            | Place::PeggedOrder { tif, .. } => Some(tif),
+            Place::MarketOrder { .. } | Place::MidMarketOrder { .. } | Place::StopOrder { .. } => {
+                None
+            }
+        }
+    }
+
+    fn stop_price(&self) -> Option<N> {
+        match self {
+            Place::StopOrder { stop_price, .. } | Place::StopLimit { stop_price, .. } => {
+                Some(*stop_price)
+            }
+            _ => None,
+        }
+    }
+
+    fn normalize_prices(&mut self) {
+        match self {
+            Place::LimitOrder { price, .. } | Place::IcebergOrder { price, .. } => {
+                *price = price.normalize()
+            }
+            Place::StopOrder { stop_price, .. } => *stop_price = stop_price.normalize(),
+            Place::StopLimit {
+                stop_price,
+                limit_price,
+                ..
+            } => {
+                *stop_price = stop_price.normalize();
+                *limit_price = limit_price.normalize();
+            }
+            Place::PeggedOrder { offset, .. } => *offset = offset.normalize(),
+            Place::MarketOrder { .. } | Place::MidMarketOrder { .. } => {}
+        }
+    }
+
+    fn max_slippage(&self) -> Option<N> {
+        match self {
+            Place::MarketOrder { max_slippage, .. } => *max_slippage,
+            _ => None,
+        }
+    }
+
+    fn notional(&self) -> bool {
+        matches!(self, Place::MarketOrder { notional: true, .. })
+    }
+
+    fn at_mid(&self) -> bool {
+        matches!(self, Place::MidMarketOrder { .. })
+    }
+
+    fn reduce_only(&self) -> bool {
+        match self {
+            Place::MarketOrder { reduce_only, .. } | Place::LimitOrder { reduce_only, .. } => {
+                *reduce_only
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub enum Command<N = Decimal> {
+    Place(Place<N>),
+    Cancel(Uuid),
+    // any that aren't on the book are left out of the result
+    CancelBatch(Vec<Uuid>),
+    CancelAccount(u64),
+    Amend {
+        uuid: Uuid,
+        new_price: N,
+        new_amount: N,
+    },
+    // take `amount` off a resting order, keeping its place in the queue
+    Reduce {
+        uuid: Uuid,
+        amount: N,
+    },
+    // keeps the account's cancel-on-disconnect orders alive
+    Heartbeat(u64),
+    // cancel one order and place another with nothing in between
+    Replace {
+        cancel_uuid: Uuid,
+        place: Place<N>,
+    },
+    Flush(),
+    // while halted nothing can be placed or amended; cancels still work
+    Halt(),
+    Resume(),
+    // collect orders without matching until Cross() clears them at one price
+    Auction(),
+    Cross(),
+    /*
+        Cancel every order and stop, e.g. before shutting down. Only the
+        book is emptied: later commands are taken as usual, so Halt() first
+        to keep new orders out.
+    */
+    DrainBook(),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub struct CommandAtTime<N = Decimal> {
+    pub now: u64,
+    pub command: Command<N>,
+}
+
+impl<N: Number> CommandAtTime<N> {
+    pub fn new(now: u64, command: Command<N>) -> CommandAtTime<N> {
+        CommandAtTime { now, command }
+    }
+}
+
+impl<N: Number> Order<N> {
+    pub fn create(place: Place<N>, now: u64) -> Order<N> {
+        let reduce_only = place.reduce_only();
+        match place {
+            Place::MarketOrder {
+                uuid,
+                account,
+                side,
+                amount,
+                ..
+            }
+            | Place::MidMarketOrder {
+                uuid,
+                account,
+                side,
+                amount,
+            }
+            | Place::StopOrder {
+                uuid,
+                account,
+                side,
+                amount,
+                ..
+            } => Order {
+                uuid,
+                account,
+                created: now,
+                side,
+                amount,
+                tif: TimeInForce::IOC,
+                price: None,
+                post_only: false,
+                display_size: None,
+                display_jitter: None,
+                min_qty: None,
+                reduce_only,
+                aon: false,
+                peg: None,
+                cancel_on_disconnect: false,
+                max_price_range: None,
+                market: true,
+                remaining_amount: amount,
+                hidden_amount: N::ZERO,
+            },
+            Place::LimitOrder {
+                uuid,
+                account,
+                side,
+                amount,
+                tif,
+                price,
+                post_only,
+                min_qty,
+                aon,
+                cancel_on_disconnect,
+                max_price_range,
+                ..
+            } => Order {
+                uuid,
+                account,
+                created: now,
+                side,
+                amount,
+                tif,
+                price: Some(price),
+                post_only,
+                display_size: None,
+                display_jitter: None,
+                min_qty,
+                reduce_only,
+                aon,
+                peg: None,
+                cancel_on_disconnect,
+                max_price_range,
+                market: false,
+                remaining_amount: amount,
+                hidden_amount: N::ZERO,
+            },
+            Place::IcebergOrder {
+                uuid,
+                account,
+                side,
+                amount,
+                price,
+                tif,
+                display_size,
+                display_jitter,
+            } => Order {
+                uuid,
+                account,
+                created: now,
+                side,
+                amount,
+                tif,
+                price: Some(price),
+                post_only: false,
+                display_size: Some(display_size),
+                display_jitter,
+                min_qty: None,
+                reduce_only,
+                aon: false,
+                peg: None,
+                cancel_on_disconnect: false,
+                max_price_range: None,
+                market: false,
+                remaining_amount: amount,
+                hidden_amount: N::ZERO,
+            },
+            Place::StopLimit {
+                uuid,
+                account,
+                side,
+                amount,
+                limit_price,
+                tif,
+                ..
+            } => Order {
+                uuid,
+                account,
+                created: now,
+                side,
+                amount,
+                tif,
+                price: Some(limit_price),
+                post_only: false,
+                display_size: None,
+                display_jitter: None,
+                min_qty: None,
+                reduce_only,
+                aon: false,
+                peg: None,
+                cancel_on_disconnect: false,
+                max_price_range: None,
+                market: false,
+                remaining_amount: amount,
+                hidden_amount: N::ZERO,
+            },
+            // priced when it is placed
+            Place::PeggedOrder {
+                uuid,
+                account,
+                side,
+                amount,
+                reference,
+                offset,
+                tif,
+            } => Order {
+                uuid,
+                account,
+                created: now,
+                side,
+                amount,
+                tif,
+                price: None,
+                post_only: false,
+                display_size: None,
+                display_jitter: None,
+                min_qty: None,
+                reduce_only,
+                aon: false,
+                peg: Some(Peg { reference, offset }),
+                cancel_on_disconnect: false,
+                max_price_range: None,
+                market: false,
+                remaining_amount: amount,
+                hidden_amount: N::ZERO,
+            },
+        }
+    }
+
+    fn resting_price(&self) -> N {
+        self.price.expect("Market orders can't rest on the book")
+    }
+
+    fn view(&self) -> OrderView<N> {
+        OrderView {
+            uuid: self.uuid,
+            account: self.account,
+            side: self.side,
+            price: self.resting_price(),
+            amount: self.amount,
+            remaining_amount: self.remaining_amount,
+            hidden_amount: self.hidden_amount,
+            created: self.created,
+            tif: self.tif,
+        }
+    }
+
+    fn total_remaining(&self) -> N {
+        self.remaining_amount + self.hidden_amount
+    }
+
+    /*
+        An iceberg shows at most `display_size`, keeping the rest hidden
+    */
+    fn show_slice(&mut self) {
+        if let Some(display_size) = self.display_size {
+            self.show(display_size);
+        }
+    }
+
+    fn show(&mut self, size: N) {
+        let total = self.total_remaining();
+        self.remaining_amount = cmp::min(size, total);
+        self.hidden_amount = total - self.remaining_amount;
+    }
+
+    /*
+        The least this order will trade in one match. Once partly filled
+        that can't be more than what is left.
+    */
+    fn min_fill(&self) -> N {
+        if self.aon {
+            return self.remaining_amount;
+        }
+        match self.min_qty {
+            Some(min_qty) => cmp::min(min_qty, self.remaining_amount),
+            None => N::ZERO,
+        }
+    }
+
+    // whether the order refuses fills below some size
+    fn has_minimum(&self) -> bool {
+        self.aon || self.min_qty.is_some()
+    }
+
+    fn validate(&self) -> Option<RejectReason> {
+        if self.amount <= N::ZERO {
+            return Some(RejectReason::InvalidAmount);
+        }
+        if let Some(min_qty) = self.min_qty {
+            if min_qty <= N::ZERO || min_qty > self.amount {
+                return Some(RejectReason::InvalidAmount);
+            }
+        }
+        if let Some(display_size) = self.display_size {
+            if display_size <= N::ZERO {
+                return Some(RejectReason::InvalidAmount);
+            }
+        }
+        if let Some(jitter) = self.display_jitter {
+            if jitter < N::ZERO || jitter >= N::from(1) {
+                return Some(RejectReason::InvalidAmount);
+            }
+        }
+        if self.max_price_range.is_some_and(|range| range < N::ZERO) {
+            return Some(RejectReason::InvalidPrice);
+        }
+        match self.tif {
+            TimeInForce::GTT(expiry) if expiry <= self.created => Some(RejectReason::InvalidExpiry),
+            _ => None,
+        }
+    }
+
+    pub fn expiry(&self) -> u64 {
+        match self.tif {
+            TimeInForce::IOC | TimeInForce::FOK => self.created,
+            TimeInForce::GTC => NEVER,
+            // an expiry past the end of time is never reached
+            TimeInForce::GTD(lifetime) => self.created.saturating_add(lifetime),
+            TimeInForce::GTT(expiry) => expiry,
+        }
+    }
+}
+
+/*
+    The third field is an engine-wide insertion sequence number, which breaks
+    ties between orders at the same price placed in the same tick
+*/
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct PriceTime<N>(N, u64, u64);
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct SidePriceTime<N>(Side, N, u64, u64);
+
+/*
+    Expiry, then the order's queue sequence number so that orders expiring
+    together go in the order they were placed, whatever their uuids
+*/
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct TimeUuid(u64, u64, Uuid);
+
+/*
+    An untriggered stop; `order` is what it turns into when triggered
+*/
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+struct StopOrder<N> {
+    order: Order<N>,
+    stop_price: N,
+}
+
+fn stop_triggered<N: Number>(side: Side, stop_price: N, last_trade_price: N) -> bool {
+    match side {
+        Side::Buy => last_trade_price >= stop_price,
+        Side::Sell => last_trade_price <= stop_price,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    OutOfOrder { now: u64, last_tick: u64 },
+    CommandLog(String), // the command couldn't be logged, so wasn't applied
+    /*
+        Matching found the book inconsistent and stopped there. Trades made
+        before it stand, but the taker isn't left resting and nothing else
+        in the command runs.
+    */
+    Invariant(String),
+    UnknownSymbol(String), // sent to an EngineRouter that has no such book
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::OutOfOrder { now, last_tick } => write!(
+                f,
+                "current_tick:{} must not be less than last_tick:{}",
+                now, last_tick
+            ),
+            EngineError::CommandLog(message) => write!(f, "command log: {}", message),
+            EngineError::Invariant(message) => write!(f, "internal error: {}", message),
+            EngineError::UnknownSymbol(symbol) => write!(f, "unknown symbol: {}", symbol),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    Allow,
+    // a taker is never matched against resting orders from its own account
+    SkipOwnOrders,
+}
+
+/*
+    Which way a mid between two ticks is rounded, when there is a price
+    tick. Passive rounds away from the other side, a buy down and a sell
+    up; Aggressive rounds towards it.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MidRounding {
+    Passive,
+    Aggressive,
+}
+
+// what Replace does when the order it would cancel isn't there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplaceMissing {
+    Reject, // the new order is rejected as UnknownOrder
+    Place,  // the new order is placed anyway
+}
+
+/*
+    Which of the orders at one price is matched first. Better prices always
+    go first whichever is chosen. Auctions are always FIFO.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TieBreak {
+    Fifo,
+    Lifo, // newest first; a refilled iceberg slice counts as new
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchingPolicy {
+    PriceTime,
+    // each price level is shared out in proportion to the makers' visible size
+    ProRata,
+}
+
+/*
+    Everything about how an Engine behaves that is fixed up front. The
+    defaults match Engine::new(): no fees, no tick or lot size, price-time
+    matching, GTC orders that never expire and GTD lifetimes of at most
+    MAX_LIFETIME. Each field also has a setter on Engine for changing it
+    later.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct EngineConfig<N = Decimal> {
+    pub matching_policy: MatchingPolicy,
+    pub tie_break: TieBreak,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub maker_bps: N,
+    pub taker_bps: N,
+    pub price_tick: N, // zero for any price
+    pub lot_size: N,   // zero for any amount
+    pub gtc_lifetime: Option<u64>,
+    pub max_gtd_lifetime: Option<u64>,
+    pub quote_precision: Option<(u32, QuoteRounding)>,
+    pub recent_trades_capacity: usize,
+    pub reference_band: Option<N>,
+    pub report_price_improvement: bool,
+    pub allow_negative_prices: bool,
+    pub max_resting_orders: Option<usize>, // on each side
+    pub replace_missing: ReplaceMissing,
+    pub report_book_deltas: bool,
+    pub jitter_seed: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+    pub reject_unknown_cancels: bool,
+    pub min_fill_ratio: Option<N>,
+    pub mid_rounding: MidRounding,
+}
+
+impl<N: Number> Default for EngineConfig<N> {
+    fn default() -> Self {
+        EngineConfig {
+            matching_policy: MatchingPolicy::PriceTime,
+            tie_break: TieBreak::Fifo,
+            self_trade_prevention: SelfTradePrevention::Allow,
+            maker_bps: N::ZERO,
+            taker_bps: N::ZERO,
+            price_tick: N::ZERO,
+            lot_size: N::ZERO,
+            gtc_lifetime: None,
+            max_gtd_lifetime: Some(MAX_LIFETIME),
+            quote_precision: None,
+            recent_trades_capacity: 0,
+            reference_band: None,
+            report_price_improvement: false,
+            allow_negative_prices: false,
+            max_resting_orders: None,
+            replace_missing: ReplaceMissing::Reject,
+            report_book_deltas: false,
+            jitter_seed: None,
+            heartbeat_timeout: None,
+            reject_unknown_cancels: false,
+            min_fill_ratio: None,
+            mid_rounding: MidRounding::Passive,
+        }
+    }
+}
+
+// called with each fill as matching makes it; see Engine::set_on_fill
+pub type FillHook<N = Decimal> = Box<dyn FnMut(&Fill<N>) + Send>;
+
+pub struct Engine<N = Decimal> {
+    buy: BTreeMap<PriceTime<N>, Order<N>>,
+    sell: BTreeMap<PriceTime<N>, Order<N>>,
+    // first key of each book, kept up to date by insert_at and take
+    best_buy: Option<PriceTime<N>>,
+    best_sell: Option<PriceTime<N>>,
+    last_tick: u64,
+    self_trade_prevention: SelfTradePrevention,
+    matching_policy: MatchingPolicy,
+    tie_break: TieBreak,
+    // fee rates in basis points of the quote amount
+    maker_bps: N,
+    taker_bps: N,
+    uuid_to_side_price_time: HashMap<Uuid, SidePriceTime<N>>,
+    // resting orders and waiting stops, by account
+    account_uuids: HashMap<u64, BTreeSet<Uuid>>,
+    positions: HashMap<u64, Position<N>>,
+    expiry_uuid: BTreeSet<TimeUuid>, // orders that never expire aren't in here
+    gtc_lifetime: Option<u64>,
+    max_gtd_lifetime: Option<u64>,
+    // zero means prices/amounts can take any value
+    price_tick: N,
+    lot_size: N,
+    sequence: u64,
+    event_sequence: u64, // last number given to an output event
+    last_trade_price: Option<N>,
+    fill_count: u64,
+    base_volume: N,
+    quote_volume: N,
+    // the most recent fills, oldest first; off when the capacity is zero
+    recent_trades: VecDeque<Fill<N>>,
+    recent_trades_capacity: usize,
+    quote_precision: Option<(u32, QuoteRounding)>,
+    halted: bool,
+    auction: bool,
+    // largest fractional move from the last trade price an order may make
+    reference_band: Option<N>,
+    report_price_improvement: bool,
+    allow_negative_prices: bool,
+    max_resting_orders: Option<usize>,
+    replace_missing: ReplaceMissing,
+    report_book_deltas: bool,
+    // visible size of each level as last reported, and levels changed since
+    levels: BTreeMap<(Side, N), N>,
+    touched: BTreeSet<(Side, N)>,
+    rng: Option<Rng>, // for iceberg jitter, which is off without it
+    heartbeat_timeout: Option<u64>,
+    // when each account with cancel-on-disconnect orders has to be heard from by
+    heartbeats: HashMap<u64, u64>,
+    reject_unknown_cancels: bool,
+    min_fill_ratio: Option<N>,
+    mid_rounding: MidRounding,
+    /*
+        sort by stop/time for BUY stops
+        sort by (-stop)/time for SELL stops
+        so the first stop on each side is the next to trigger
+    */
+    buy_stops: BTreeMap<PriceTime<N>, StopOrder<N>>,
+    sell_stops: BTreeMap<PriceTime<N>, StopOrder<N>>,
+    uuid_to_stop: HashMap<Uuid, SidePriceTime<N>>,
+    pegged: BTreeSet<Uuid>, // resting pegged orders, moved by repeg
+    command_log: Option<CommandLog>,
+    on_fill: Option<FillHook<N>>,
+    // set when matching refuses an impossible trade, reported by call
+    fault: Option<String>,
+}
+
+fn crossed<N: Number>(taker: &Order<N>, maker: &Order<N>) -> bool {
+    if taker.remaining_amount.is_zero() {
+        return false;
+    };
+    match (taker.side, taker.price) {
+        (_, None) => true,
+        (Side::Buy, Some(price)) => price >= maker.resting_price(),
+        (Side::Sell, Some(price)) => price <= maker.resting_price(),
+    }
+}
+
+fn self_trade<N: Number>(stp: SelfTradePrevention, taker: &Order<N>, maker: &Order<N>) -> bool {
+    stp == SelfTradePrevention::SkipOwnOrders && taker.account == maker.account
+}
+
+fn multiple_of<N: Number>(value: N, step: N) -> bool {
+    step.is_zero() || (value % step).is_zero()
+}
+
+// to a multiple of `step`, up or down; zero leaves it as it is
+fn round_to_step<N: Number>(value: N, step: N, up: bool) -> N {
+    if multiple_of(value, step) {
+        return value;
+    }
+    let rem = value % step;
+    let down = if rem > N::ZERO {
+        value - rem
+    } else {
+        value - rem - step
+    };
+    if up {
+        down + step
+    } else {
+        down
+    }
+}
+
+fn fee<N: Number>(quote_amount: N, bps: N) -> N {
+    quote_amount * bps / N::from(10000)
+}
+
+/*
+    A trade that would take either order below zero means the book is
+    already inconsistent, so it is refused and nothing is changed
+*/
+fn execute<N: Number>(
+    taker: &mut Order<N>,
+    maker: &mut Order<N>,
+    base_amount: N,
+    maker_bps: N,
+    taker_bps: N,
+) -> Result<Fill<N>, String> {
+    if base_amount <= N::ZERO
+        || base_amount > taker.remaining_amount
+        || base_amount > maker.remaining_amount
+    {
+        return Err(format!(
+            "cannot trade {} between {} ({} left) and {} ({} left)",
+            base_amount, taker.uuid, taker.remaining_amount, maker.uuid, maker.remaining_amount
+        ));
+    }
+    let price = maker.resting_price();
+    taker.remaining_amount -= base_amount;
+    maker.remaining_amount -= base_amount;
+    Ok(Fill {
+        base_amount,
+        price,
+        maker_uuid: maker.uuid,
+        taker_uuid: taker.uuid,
+        maker_account: maker.account,
+        taker_account: taker.account,
+        taker_side: taker.side,
+        maker_fee: fee(base_amount * price, maker_bps),
+        taker_fee: fee(base_amount * price, taker_bps),
+        sequence: 0,
+        settlement_amount: base_amount * price,
+        price_improvement: None,
+    })
+}
+
+/*
+    Share `quantity` between `sizes` in proportion, rounded down to the
+    finest scale among the inputs. Whatever is left over by rounding goes
+    to the earliest orders that still have room.
+*/
+fn pro_rata<N: Number>(quantity: N, sizes: &[N]) -> Vec<N> {
+    let total: N = sizes.iter().sum();
+    if quantity >= total {
+        return sizes.to_vec();
+    }
+    let scale = sizes
+        .iter()
+        .map(|size| size.scale())
+        .fold(quantity.scale(), cmp::max);
+    let mut allocations: Vec<N> = sizes
+        .iter()
+        .map(|size| (quantity * *size / total).truncate_to(scale))
+        .collect();
+    let mut leftover = quantity - allocations.iter().sum::<N>();
+    for (allocation, size) in allocations.iter_mut().zip(sizes) {
+        let extra = cmp::min(leftover, *size - *allocation);
+        *allocation += extra;
+        leftover -= extra;
+    }
+    allocations
+}
+
+fn rejection<N: Number>(uuid: Uuid, reason: RejectReason) -> MatchResult<N> {
+    let mut result = MatchResult::default();
+    result.closed.insert(uuid, CloseReason::Rejected);
+    result.reject(uuid, reason);
+    result
+}
+
+fn cancellation<N: Number>(uuids: impl IntoIterator<Item = Uuid>) -> MatchResult<N> {
+    let mut result = MatchResult::default();
+    for uuid in uuids {
+        result.canceled(uuid, CloseReason::CanceledByUser);
+    }
+    result
+}
+
+// `expired` comes from the flush that ran before the command
+fn merge<N: Number>(mut r1: MatchResult<N>, mut expired: Vec<ExpiredOrder<N>>) -> MatchResult<N> {
+    for order in &expired {
+        r1.closed.insert(order.uuid, CloseReason::Expired);
+    }
+    let mut steps = vec![Step::Expired; expired.len()];
+    steps.append(&mut r1.steps);
+    r1.steps = steps;
+    expired.append(&mut r1.expired);
+    r1.expired = expired;
+    r1
+}
+
+// only Decimal has `new`, so that Engine::new() needs no type annotation
+impl Engine {
+    pub fn new() -> Engine {
+        Engine::with_config(EngineConfig::default())
+    }
+}
+
+impl<N: Number> Default for Engine<N> {
+    fn default() -> Self {
+        Engine::with_config(EngineConfig::default())
+    }
+}
+
+impl<N: Number> Engine<N> {
+    fn _match(&mut self, taker: &mut Order<N>) -> MatchResult<N> {
+        /*
+            A taker with a price range has its limit pulled in to the edge
+            of the range for as long as it matches. If that edge, and not
+            its own limit, is what stops it, the rest is cancelled.
+        */
+        debug!(
+            "match taker={} side={} amount={} price={:?}",
+            taker.uuid, taker.side, taker.remaining_amount, taker.price
+        );
+        let limit = taker.price;
+        let ranged = taker
+            .max_price_range
+            .and_then(|range| self.range_limit(taker, range));
+        if ranged.is_some() {
+            taker.price = ranged;
+        }
+        let mut result = self.match_taker(taker);
+        taker.price = limit;
+        if ranged.is_some()
+            && !result.closed.contains_key(&taker.uuid)
+            && !taker.remaining_amount.is_zero()
+            && self.would_cross(taker)
+        {
+            result.unfilled(taker);
+        }
+
+        if let (true, false, Some(limit)) = (self.report_price_improvement, taker.market, limit) {
+            for fill in &mut result.fills {
+                fill.price_improvement = Some((limit - fill.price).abs());
+            }
+        }
+        result
+    }
+
+    /*
+        How much of the base currency `notional` of quote buys (or sells
+        for) when taken from the book as `taker` would take it: whole
+        levels while the notional lasts, then as much of the next as it
+        covers, cut down to the lot size. Without one it is cut down to the
+        finest scale of the notional and the amounts it is taken from, so
+        no maker is left with a sliver finer than it was given. Makers the
+        taker would skip for self-trade prevention aren't counted; any it
+        can't trade for their minimum or all-or-none may leave it short of
+        the notional.
+    */
+    fn notional_base(&self, taker: &Order<N>, notional: N) -> N {
+        let stp = self.self_trade_prevention;
+        let mut left = notional;
+        let mut base = N::ZERO;
+        let mut scale = notional.scale();
+        let mut makers = self.book(other_side(taker.side)).values().peekable();
+        while let Some(first) = makers.peek() {
+            if !crossed(taker, first) || left <= N::ZERO {
+                break;
+            }
+            let price = first.resting_price();
+            let mut level = N::ZERO;
+            while let Some(maker) = makers.next_if(|maker| maker.resting_price() == price) {
+                if !self_trade(stp, taker, maker) {
+                    level += maker.total_remaining();
+                    scale = cmp::max(scale, maker.total_remaining().scale());
+                }
+            }
+            if level * price <= left {
+                base += level;
+                left -= level * price;
+            } else if self.lot_size.is_zero() {
+                return (base + (left / price).truncate_to(scale)).normalize();
+            } else {
+                return base + round_to_step(left / price, self.lot_size, false).normalize();
+            }
+        }
+        base
+    }
+
+    // the price of the first maker the taker would trade with, passing over any it would skip
+    fn first_match_price(&self, taker: &Order<N>) -> Option<N> {
+        let stp = self.self_trade_prevention;
+        let first = self
+            .book(other_side(taker.side))
+            .values()
+            .take_while(|maker| crossed(taker, maker))
+            .find(|maker| {
+                !self_trade(stp, taker, maker) && taker.remaining_amount >= maker.min_fill()
+            })?;
+        Some(first.resting_price())
+    }
+
+    // the limit `range` from the first maker the taker would trade with, or its own if tighter
+    fn range_limit(&self, taker: &Order<N>, range: N) -> Option<N> {
+        let first = self.first_match_price(taker)?;
+        Some(match (taker.side, taker.price) {
+            (Side::Buy, None) => first + range,
+            (Side::Sell, None) => first - range,
+            (Side::Buy, Some(price)) => cmp::min(price, first + range),
+            (Side::Sell, Some(price)) => cmp::max(price, first - range),
+        })
+    }
+
+    fn match_taker(&mut self, taker: &mut Order<N>) -> MatchResult<N> {
+        let mut result = MatchResult::default();
+
+        /*
+            FOK is all or nothing, so check the whole amount is available
+            before generating any fills
+        */
+        if let TimeInForce::FOK = taker.tif {
+            if self.crossed_liquidity(taker) < taker.remaining_amount {
+                result.unfilled(taker);
+                return result;
+            }
+        }
+
+        /*
+            Short of its minimum quantity an order doesn't trade at all: IOC
+            is closed, anything else rests until there is enough liquidity
+        */
+        if taker.has_minimum() && self.crossed_liquidity(taker) < taker.min_fill() {
+            if let TimeInForce::IOC | TimeInForce::FOK = taker.tif {
+                result.unfilled(taker);
+            }
+            return result;
+        }
+
+        /*
+            A pass stops early when an iceberg maker needs refilling, which
+            moves it to the back of its price level before matching resumes
+        */
+        loop {
+            let refills: Vec<Uuid> = match self.matching_policy {
+                MatchingPolicy::PriceTime => {
+                    self.match_pass(taker, &mut result).into_iter().collect()
+                }
+                MatchingPolicy::ProRata => self.pro_rata_pass(taker, &mut result),
+            };
+            /*
+                Filled makers leave the book before anything else happens,
+                so an order with nothing left is never seen resting. The
+                taker isn't on the book, so it is unaffected.
+            */
+            for uuid in result.closed.keys() {
+                self.remove(*uuid);
+            }
+            // after a fault nothing more is matched, but the book is left well formed
+            let done = refills.is_empty() || self.fault.is_some();
+            for uuid in refills {
+                let mut maker = self.take(uuid).expect("Data structure mismatch");
+                self.show_slice(&mut maker);
+                self.insert_at(maker, taker.created);
+            }
+            if done {
+                break;
+            }
+        }
+
+        if let TimeInForce::IOC | TimeInForce::FOK = taker.tif {
+            result.unfilled(taker);
+        }
+        self.tally(&mut result);
+        result
+    }
+
+    /*
+        Totals and positions are brought up to date with the fills, and any
+        resting reduce-only order that is now bigger than what its account
+        has left to reduce is cut down. One cut to nothing is cancelled and
+        taken off the book.
+    */
+    fn tally(&mut self, result: &mut MatchResult<N>) {
+        let mut accounts = BTreeSet::new();
+        for fill in &mut result.fills {
+            if let Some((precision, rounding)) = self.quote_precision {
+                fill.settlement_amount = fill.rounded_quote_amount(precision, rounding);
+            }
+            self.fill_count += 1;
+            self.base_volume += fill.base_amount;
+            self.quote_volume += fill.quote_amount();
+
+            let bought = match fill.taker_side {
+                Side::Buy => fill.base_amount,
+                Side::Sell => -fill.base_amount,
+            };
+            let price = fill.price;
+            self.positions
+                .entry(fill.taker_account)
+                .or_default()
+                .apply(bought, price);
+            self.positions
+                .entry(fill.maker_account)
+                .or_default()
+                .apply(-bought, price);
+            accounts.extend([fill.taker_account, fill.maker_account]);
+        }
+
+        for account in accounts {
+            for uuid in self.account_queue(account) {
+                let reducible = match self.get(uuid) {
+                    Some(order) if order.reduce_only => self.reducible(account, order.side),
+                    _ => continue,
+                };
+                let order = self.get_mut(uuid).expect("Data structure mismatch");
+                if order.remaining_amount > reducible {
+                    order.remaining_amount = reducible;
+                }
+                if order.remaining_amount.is_zero() {
+                    result.canceled(uuid, CloseReason::ReduceOnly);
+                    self.remove(uuid);
+                }
+            }
+        }
+    }
+
+    // how much of a `side` order would only reduce the account's position
+    fn reducible(&self, account: u64, side: Side) -> N {
+        let position = self.position(account).unwrap_or_default().net;
+        match side {
+            Side::Buy => cmp::max(-position, N::ZERO),
+            Side::Sell => cmp::max(position, N::ZERO),
+        }
+    }
+
+    // None for an account that has never traded
+    pub fn position(&self, account: u64) -> Option<Position<N>> {
+        self.positions.get(&account).copied()
+    }
+
+    /*
+        End an auction by executing everything that crosses at one price:
+        the one that trades the most volume, then leaves the smallest
+        imbalance, then the lowest. Orders fill in priority order on each
+        side. Nobody aggressed, so the later of the two orders in a fill is
+        reported as the taker. Self-trade prevention and minimum quantities
+        don't apply. Afterwards the engine matches continuously again.
+    */
+    pub fn cross(&mut self, now: u64) -> MatchResult<N> {
+        self.auction = false;
+        let mut result = MatchResult::default();
+        let price = match self.clearing_price() {
+            Some(price) => price,
+            None => return result,
+        };
+
+        let mut buys = self
+            .buy
+            .values()
+            .take_while(|order| order.resting_price() >= price)
+            .map(|order| (order.uuid, order.total_remaining()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut sells = self
+            .sell
+            .values()
+            .take_while(|order| order.resting_price() <= price)
+            .map(|order| (order.uuid, order.total_remaining()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut buy = buys.next();
+        let mut sell = sells.next();
+        while let (Some((buy_uuid, buy_left)), Some((sell_uuid, sell_left))) = (&mut buy, &mut sell)
+        {
+            let amount = cmp::min(*buy_left, *sell_left);
+            let fill = self.auction_fill(*buy_uuid, *sell_uuid, amount, price);
+            if let Some(on_fill) = &mut self.on_fill {
+                on_fill(&fill);
+            }
+            result.fill(fill);
+            *buy_left -= amount;
+            *sell_left -= amount;
+            if buy_left.is_zero() {
+                result.filled(*buy_uuid);
+                self.remove(*buy_uuid);
+                buy = buys.next();
+            }
+            if sell_left.is_zero() {
+                result.filled(*sell_uuid);
+                self.remove(*sell_uuid);
+                sell = sells.next();
+            }
+        }
+
+        self.tally(&mut result);
+        self.last_trade_price = Some(price);
+        result.append(self.trigger_stops(now));
+        result
+    }
+
+    fn clearing_price(&self) -> Option<N> {
+        let prices: BTreeSet<N> = self
+            .buy
+            .values()
+            .chain(self.sell.values())
+            .map(Order::resting_price)
+            .collect();
+        let mut best: Option<(N, N, N)> = None; // price, volume, imbalance
+        for price in prices {
+            let demand: N = self
+                .buy
+                .values()
+                .take_while(|order| order.resting_price() >= price)
+                .map(Order::total_remaining)
+                .sum();
+            let supply: N = self
+                .sell
+                .values()
+                .take_while(|order| order.resting_price() <= price)
+                .map(Order::total_remaining)
+                .sum();
+            let volume = cmp::min(demand, supply);
+            let imbalance = (demand - supply).abs();
+            // prices are ascending, so a tie keeps the lower one
+            let better = match best {
+                None => !volume.is_zero(),
+                Some((_, best_volume, best_imbalance)) => {
+                    volume > best_volume || (volume == best_volume && imbalance < best_imbalance)
+                }
+            };
+            if better {
+                best = Some((price, volume, imbalance));
+            }
+        }
+        best.map(|(price, _, _)| price)
+    }
+
+    fn auction_fill(&mut self, buy: Uuid, sell: Uuid, amount: N, price: N) -> Fill<N> {
+        let queued = |uuid: Uuid| {
+            let SidePriceTime(_, _, time, sequence) = self.uuid_to_side_price_time[&uuid];
+            (time, sequence)
+        };
+        let (maker_uuid, taker_uuid, taker_side) = if queued(sell) > queued(buy) {
+            (buy, sell, Side::Sell)
+        } else {
+            (sell, buy, Side::Buy)
+        };
+        let account = |uuid: Uuid| self.get(uuid).expect("Data structure mismatch").account;
+        let (maker_account, taker_account) = (account(maker_uuid), account(taker_uuid));
+        let price_improvement = if self.report_price_improvement {
+            let limit = self
+                .get(taker_uuid)
+                .filter(|taker| !taker.market)
+                .and_then(|taker| taker.price);
+            limit.map(|limit| (limit - price).abs())
+        } else {
+            None
+        };
+        for uuid in [buy, sell] {
+            let order = self.get_mut(uuid).expect("Data structure mismatch");
+            debug_assert!(
+                amount <= order.total_remaining(),
+                "auction overfills {}",
+                uuid
+            );
+            order.remaining_amount = order.total_remaining() - amount;
+            order.hidden_amount = N::ZERO;
+            order.show_slice();
+        }
+        Fill {
+            base_amount: amount,
+            price,
+            maker_uuid,
+            taker_uuid,
+            maker_account,
+            taker_account,
+            taker_side,
+            maker_fee: fee(amount * price, self.maker_bps),
+            taker_fee: fee(amount * price, self.taker_bps),
+            sequence: 0,
+            settlement_amount: amount * price,
+            price_improvement,
+        }
+    }
+
+    fn match_pass(&mut self, taker: &mut Order<N>, result: &mut MatchResult<N>) -> Option<Uuid> {
+        let stp = self.self_trade_prevention;
+        let (maker_bps, taker_bps) = (self.maker_bps, self.taker_bps);
+        let mut last_trade_price = self.last_trade_price;
+        let mut refill = None;
+        let mut fault = None;
+        let lifo = self.tie_break == TieBreak::Lifo;
+        // out of the way of the borrow of the book, and put back below
+        let mut on_fill = self.on_fill.take();
+        let mut makers = self.resting(other_side(taker.side)).values_mut().peekable();
+        let mut level = Vec::new();
+        'levels: while let Some(first) = makers.peek() {
+            if !crossed(taker, first) {
+                break;
+            }
+            let price = first.resting_price();
+            while let Some(maker) = makers.next_if(|maker| maker.resting_price() == price) {
+                level.push(maker);
+            }
+            if lifo {
+                level.reverse();
+            }
+            for maker in level.drain(..) {
+                debug_assert!(
+                    !maker.remaining_amount.is_zero(),
+                    "empty order {} resting",
+                    maker.uuid
+                );
+                if self_trade(stp, taker, maker) {
+                    continue;
+                }
+                if taker.remaining_amount < maker.min_fill() {
+                    continue;
+                }
+
+                let base_amount = cmp::min(taker.remaining_amount, maker.remaining_amount);
+                let fill = match execute(taker, maker, base_amount, maker_bps, taker_bps) {
+                    Ok(fill) => fill,
+                    Err(message) => {
+                        fault = Some(message);
+                        break 'levels;
+                    }
+                };
+                last_trade_price = Some(fill.price);
+                if let Some(on_fill) = &mut on_fill {
+                    on_fill(&fill);
+                }
+
+                result.fill(fill); //now 'fill' belongs to 'result'
+
+                if taker.remaining_amount.is_zero() {
+                    result.filled(taker.uuid);
+                }
+                if maker.remaining_amount.is_zero() {
+                    if maker.hidden_amount.is_zero() {
+                        result.filled(maker.uuid);
+                    } else {
+                        refill = Some(maker.uuid);
+                        break 'levels;
+                    }
+                }
+                if taker.remaining_amount.is_zero() {
+                    break 'levels;
+                }
+            }
+        }
+        self.last_trade_price = last_trade_price;
+        self.fault = self.fault.take().or(fault);
+        self.on_fill = on_fill;
+        refill
+    }
+
+    fn pro_rata_pass(&mut self, taker: &mut Order<N>, result: &mut MatchResult<N>) -> Vec<Uuid> {
+        let stp = self.self_trade_prevention;
+        let (maker_bps, taker_bps) = (self.maker_bps, self.taker_bps);
+        let mut last_trade_price = self.last_trade_price;
+        let mut refills = Vec::new();
+        let mut fault = None;
+        let lifo = self.tie_break == TieBreak::Lifo;
+        let mut on_fill = self.on_fill.take();
+        let mut makers = self.resting(other_side(taker.side)).values_mut().peekable();
+        while let Some(first) = makers.peek() {
+            if !crossed(taker, first) {
+                break;
+            }
+            let price = first.resting_price();
+            let mut level = Vec::new();
+            while let Some(maker) = makers.next_if(|maker| maker.resting_price() == price) {
+                debug_assert!(
+                    !maker.remaining_amount.is_zero(),
+                    "empty order {} resting",
+                    maker.uuid
+                );
+                if !self_trade(stp, taker, maker) && taker.remaining_amount >= maker.min_fill() {
+                    level.push(maker);
+                }
+            }
+            // so any rounding leftover goes to the newest orders first
+            if lifo {
+                level.reverse();
+            }
+
+            /*
+                An all-or-none maker can't be given a share, so unless the
+                taker takes the whole level it is passed over
+            */
+            let level_total: N = level.iter().map(|maker| maker.remaining_amount).sum();
+            if taker.remaining_amount < level_total {
+                level.retain(|maker| !maker.aon);
+            }
+
+            let sizes: Vec<N> = level.iter().map(|maker| maker.remaining_amount).collect();
+            let allocations = pro_rata(taker.remaining_amount, &sizes);
+            for (maker, base_amount) in level.into_iter().zip(allocations) {
+                if base_amount.is_zero() {
+                    continue;
+                }
+                match execute(taker, maker, base_amount, maker_bps, taker_bps) {
+                    Ok(fill) => {
+                        if let Some(on_fill) = &mut on_fill {
+                            on_fill(&fill);
+                        }
+                        result.fill(fill)
+                    }
+                    Err(message) => {
+                        fault = Some(message);
+                        break;
+                    }
+                }
+                last_trade_price = Some(price);
+                if maker.remaining_amount.is_zero() {
+                    if maker.hidden_amount.is_zero() {
+                        result.filled(maker.uuid);
+                    } else {
+                        refills.push(maker.uuid);
+                    }
+                }
+            }
+
+            if taker.remaining_amount.is_zero() {
+                result.filled(taker.uuid);
+                break;
+            }
+            if fault.is_some() {
+                break;
+            }
+            // icebergs are refilled before the rest of the book is matched
+            if !refills.is_empty() {
+                break;
+            }
+        }
+        self.last_trade_price = last_trade_price;
+        self.fault = self.fault.take().or(fault);
+        self.on_fill = on_fill;
+        refills
+    }
+
+    fn would_cross(&self, taker: &Order<N>) -> bool {
+        match self.top(other_side(taker.side)) {
+            Some(maker) => crossed(taker, maker),
+            None => false,
+        }
+    }
+
+    /*
+        How much of `taker` would trade if it were matched now. The book is
+        walked as the matching policy would walk it, since whether a maker
+        with a minimum can trade depends on how much the taker has left by
+        the time it gets there.
+    */
+    fn crossed_liquidity(&self, taker: &Order<N>) -> N {
+        let stp = self.self_trade_prevention;
+        let mut left = taker.remaining_amount;
+        let mut makers = self.book(other_side(taker.side)).values().peekable();
+        let mut queue = Vec::new();
+        while let Some(first) = makers.peek() {
+            if !crossed(taker, first) || left.is_zero() {
+                break;
+            }
+            let price = first.resting_price();
+            let mut level = N::ZERO;
+            let mut without_aon = N::ZERO;
+            while let Some(maker) = makers.next_if(|maker| maker.resting_price() == price) {
+                queue.push(maker);
+            }
+            if self.tie_break == TieBreak::Lifo {
+                queue.reverse();
+            }
+            for maker in queue.drain(..) {
+                if self_trade(stp, taker, maker) || left < maker.min_fill() {
+                    continue;
+                }
+                match self.matching_policy {
+                    MatchingPolicy::PriceTime => left -= cmp::min(left, maker.total_remaining()),
+                    MatchingPolicy::ProRata => {
+                        level += maker.total_remaining();
+                        if !maker.aon {
+                            without_aon += maker.total_remaining();
+                        }
+                    }
+                }
+            }
+            // see pro_rata_pass for why all-or-none makers may be left out
+            if left >= level {
+                left -= level;
+            } else {
+                left -= cmp::min(left, without_aon);
+            }
+        }
+        taker.remaining_amount - left
+    }
+
+    pub fn best_bid(&self) -> Option<(N, N)> {
+        self.best(Side::Buy)
+    }
+
+    pub fn best_ask(&self) -> Option<(N, N)> {
+        self.best(Side::Sell)
+    }
+
+    pub fn spread(&self) -> Option<N> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => Some(ask - bid),
+            _ => None,
+        }
+    }
+
+    /*
+        The book as it stands, without a time: nothing is flushed, so an
+        order whose expiry has passed since the last call still shows until
+        the next one. `levels` is how deep to go on each side.
+    */
+    pub fn peek(&self, levels: usize) -> BookView<N> {
+        BookView {
+            best_bid: self.best_bid(),
+            best_ask: self.best_ask(),
+            spread: self.spread(),
+            bids: self.depth(Side::Buy, levels),
+            asks: self.depth(Side::Sell, levels),
+        }
+    }
+
+    /*
+        Visible amount resting on `side` at `price_limit` or better: for the
+        sell book, the most a buy limited to that price could see to take.
+        Hidden iceberg reserves, self-trade prevention and minimum
+        quantities aren't taken into account.
+    */
+    pub fn available_liquidity(&self, side: Side, price_limit: N) -> N {
+        self.book(side)
+            .values()
+            .take_while(|order| match side {
+                Side::Sell => order.resting_price() <= price_limit,
+                Side::Buy => order.resting_price() >= price_limit,
+            })
+            .map(|order| order.remaining_amount)
+            .sum()
+    }
+
+    /*
+        The `n` resting orders on `side` with the most visible remaining,
+        largest first; of equal sizes, the one ahead in the book comes first.
+        Walks the whole side, O(orders + n log n).
+    */
+    pub fn largest_orders(&self, side: Side, n: usize) -> Vec<OrderView<N>> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let mut orders: Vec<(usize, &Order<N>)> = self.book(side).values().enumerate().collect();
+        let largest = |(a, x): &(usize, &Order<N>), (b, y): &(usize, &Order<N>)| {
+            y.remaining_amount.cmp(&x.remaining_amount).then(a.cmp(b))
+        };
+        if n < orders.len() {
+            orders.select_nth_unstable_by(n - 1, largest);
+            orders.truncate(n);
+        }
+        orders.sort_unstable_by(largest);
+        orders.into_iter().map(|(_, order)| order.view()).collect()
+    }
+
+    // visible amount resting on `side`; hidden iceberg reserves aren't counted
+    pub fn total_volume(&self, side: Side) -> N {
+        self.book(side)
+            .values()
+            .map(|order| order.remaining_amount)
+            .sum()
+    }
+
+    /*
+        (buy - sell) / (buy + sell) over total_volume, from -1 (all sells)
+        to 1 (all buys); None for an empty book
+    */
+    pub fn book_imbalance(&self) -> Option<N> {
+        let buy = self.total_volume(Side::Buy);
+        let sell = self.total_volume(Side::Sell);
+        let total = buy + sell;
+        if total.is_zero() {
+            return None;
+        }
+        Some((buy - sell) / total)
+    }
+
+    pub fn stats(&self) -> EngineStats<N> {
+        EngineStats {
+            fills: self.fill_count,
+            base_volume: self.base_volume,
+            quote_volume: self.quote_volume,
+            last_trade_price: self.last_trade_price,
+            resting_orders: self.buy.len() + self.sell.len(),
+        }
+    }
+
+    // the price comes from the cache, so only the level itself is walked
+    fn best(&self, side: Side) -> Option<(N, N)> {
+        let price = self.top(side)?.resting_price();
+        Some((price, self.level_size(side, price)))
+    }
+
+    /*
+        (price, total remaining amount) for up to `levels` price levels,
+        best price first
+    */
+    pub fn depth(&self, side: Side, levels: usize) -> Vec<(N, N)> {
+        let mut depth: Vec<(N, N)> = Vec::new();
+        for order in self.book(side).values() {
+            let price = order.resting_price();
+            match depth.last_mut() {
+                Some((level, size)) if *level == price => *size += order.remaining_amount,
+                _ => {
+                    if depth.len() == levels {
+                        break;
+                    }
+                    depth.push((price, order.remaining_amount));
+                }
+            }
+        }
+        depth
+    }
+
+    fn book(&self, side: Side) -> &BTreeMap<PriceTime<N>, Order<N>> {
+        match side {
+            Side::Buy => &self.buy,
+            Side::Sell => &self.sell,
+        }
+    }
+
+    fn resting(&mut self, side: Side) -> &mut BTreeMap<PriceTime<N>, Order<N>> {
+        match side {
+            Side::Buy => &mut self.buy,
+            Side::Sell => &mut self.sell,
+        }
+    }
+    pub fn with_fees(maker_bps: N, taker_bps: N) -> Engine<N> {
+        Engine::with_config(EngineConfig {
+            maker_bps,
+            taker_bps,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: EngineConfig<N>) -> Engine<N> {
+        Engine {
+            buy: BTreeMap::new(),
+            sell: BTreeMap::new(),
+            best_buy: None,
+            best_sell: None,
+            last_tick: 0,
+            self_trade_prevention: config.self_trade_prevention,
+            matching_policy: config.matching_policy,
+            tie_break: config.tie_break,
+            maker_bps: config.maker_bps,
+            taker_bps: config.taker_bps,
+            uuid_to_side_price_time: HashMap::new(),
+            account_uuids: HashMap::new(),
+            positions: HashMap::new(),
+            expiry_uuid: BTreeSet::new(),
+            gtc_lifetime: config.gtc_lifetime,
+            max_gtd_lifetime: config.max_gtd_lifetime,
+            price_tick: config.price_tick,
+            lot_size: config.lot_size,
+            sequence: 0,
+            event_sequence: 0,
+            last_trade_price: None,
+            fill_count: 0,
+            base_volume: N::ZERO,
+            quote_volume: N::ZERO,
+            recent_trades: VecDeque::new(),
+            recent_trades_capacity: config.recent_trades_capacity,
+            quote_precision: config.quote_precision,
+            halted: false,
+            auction: false,
+            reference_band: config.reference_band,
+            report_price_improvement: config.report_price_improvement,
+            allow_negative_prices: config.allow_negative_prices,
+            max_resting_orders: config.max_resting_orders,
+            replace_missing: config.replace_missing,
+            report_book_deltas: config.report_book_deltas,
+            levels: BTreeMap::new(),
+            touched: BTreeSet::new(),
+            rng: config.jitter_seed.map(Rng::new),
+            heartbeat_timeout: config.heartbeat_timeout,
+            heartbeats: HashMap::new(),
+            reject_unknown_cancels: config.reject_unknown_cancels,
+            min_fill_ratio: config.min_fill_ratio,
+            mid_rounding: config.mid_rounding,
+            buy_stops: BTreeMap::new(),
+            sell_stops: BTreeMap::new(),
+            uuid_to_stop: HashMap::new(),
+            pegged: BTreeSet::new(),
+            command_log: None,
+            on_fill: None,
+            fault: None,
+        }
+    }
+
+    /*
+        with_config, with room made up front for `orders` resting orders
+        from `accounts` accounts; see reserve
+    */
+    pub fn with_capacity(config: EngineConfig<N>, orders: usize, accounts: usize) -> Engine<N> {
+        let mut engine = Engine::with_config(config);
+        engine.reserve(orders, accounts);
+        engine
+    }
+
+    /*
+        Make room in the hash maps indexing orders by uuid and by account,
+        so they don't have to grow while trading. Only a performance hint:
+        nothing behaves differently. The book itself is kept in BTreeMaps,
+        which allocate per node and can't be sized ahead.
+    */
+    pub fn reserve(&mut self, orders: usize, accounts: usize) {
+        self.uuid_to_side_price_time.reserve(orders);
+        self.account_uuids.reserve(accounts);
+        self.positions.reserve(accounts);
+    }
+
+    /*
+        Every command accepted by `call` from now on is written to `log`
+        before it is applied
+    */
+    pub fn set_command_log(&mut self, log: CommandLog) {
+        self.command_log = Some(log);
+    }
+
+    /*
+        `on_fill` sees every fill the moment matching makes it, before the
+        rest of the match is done: in the order of MatchResult::fills, and
+        so also in the order of their sequence numbers, which aren't set
+        yet when it is called (they are all 0). It isn't part of a snapshot.
+    */
+    pub fn set_on_fill(&mut self, on_fill: Option<FillHook<N>>) {
+        self.on_fill = on_fill;
+    }
+
+    pub fn set_self_trade_prevention(&mut self, stp: SelfTradePrevention) {
+        self.self_trade_prevention = stp;
+    }
+
+    /*
+        By default GTC orders never expire. A venue that wants them capped
+        can give them a lifetime, e.g. Some(MAX_LIFETIME); this applies to
+        orders already resting as well as new ones.
+    */
+    pub fn set_gtc_lifetime(&mut self, lifetime: Option<u64>) {
+        self.gtc_lifetime = lifetime;
+        self.rebuild_expiry_index();
+    }
+
+    // work out every resting order's expiry afresh, dropping whatever was there
+    pub fn rebuild_expiry_index(&mut self) {
+        self.expiry_uuid = self.expiry_index();
+    }
+
+    fn expiry_index(&self) -> BTreeSet<TimeUuid> {
+        self.buy
+            .iter()
+            .chain(self.sell.iter())
+            .map(|(key, order)| TimeUuid(self.expiry(order), key.2, order.uuid))
+            .filter(|TimeUuid(expiry, _, _)| *expiry != NEVER)
+            .collect()
+    }
+
+    /*
+        GTD orders with a longer lifetime are rejected as LifetimeTooLong,
+        rather than cut short. The default is MAX_LIFETIME, the same bound
+        a venue would give GTC orders; None allows any lifetime. Orders
+        already resting are left alone.
+    */
+    pub fn set_max_gtd_lifetime(&mut self, lifetime: Option<u64>) {
+        self.max_gtd_lifetime = lifetime;
+    }
+
+    fn lifetime_allowed(&self, tif: TimeInForce) -> bool {
+        match (tif, self.max_gtd_lifetime) {
+            (TimeInForce::GTD(lifetime), Some(max)) => lifetime <= max,
+            _ => true,
+        }
+    }
+
+    /*
+        Keep the last `capacity` fills for recent_trades, or none if zero
+    */
+    pub fn set_recent_trades_capacity(&mut self, capacity: usize) {
+        self.recent_trades_capacity = capacity;
+        while self.recent_trades.len() > capacity {
+            self.recent_trades.pop_front();
+        }
+        self.recent_trades.shrink_to(capacity);
+    }
+
+    // up to `n` of the kept fills, newest first
+    pub fn recent_trades(&self, n: usize) -> Vec<Fill<N>> {
+        self.recent_trades.iter().rev().take(n).cloned().collect()
+    }
+
+    fn record_trades(&mut self, fills: &[Fill<N>]) {
+        if self.recent_trades_capacity == 0 {
+            return;
+        }
+        for fill in fills {
+            if self.recent_trades.len() == self.recent_trades_capacity {
+                self.recent_trades.pop_front();
+            }
+            self.recent_trades.push_back(fill.clone());
+        }
+    }
+
+    // fills' settlement_amount is rounded to `precision` decimal places
+    pub fn set_quote_precision(&mut self, precision: u32, rounding: QuoteRounding) {
+        self.quote_precision = Some((precision, rounding));
+    }
+
+    /*
+        Halting stops all matching: places and amends are rejected, while
+        resting orders stay where they are and can still be cancelled
+    */
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    /*
+        In auction mode orders rest without matching, even if they cross,
+        until `cross` is called
+    */
+    pub fn set_auction_mode(&mut self, auction: bool) {
+        self.auction = auction;
+    }
+
+    /*
+        With a band of e.g. 0.1, limit orders priced more than 10% away from
+        the last trade price are rejected, and market orders stop matching
+        at that distance. Nothing is checked until there has been a trade.
+        A stop-limit's price is checked when it is placed, not when it
+        triggers.
+    */
+    pub fn set_reference_band(&mut self, band: Option<N>) {
+        self.reference_band = band;
+    }
+
+    /*
+        Fills still execute at the maker's price; this only adds to each
+        fill how much better that was than the taker's limit. Market orders
+        have no limit, so their fills never carry one.
+    */
+    pub fn set_report_price_improvement(&mut self, report: bool) {
+        self.report_price_improvement = report;
+    }
+
+    /*
+        Caps how many orders may rest on each side of the book. A new order
+        that would rest on a full side is rejected, or if it has already
+        traded, what it has left is dropped. Orders that only take liquidity
+        aren't affected, and nothing already resting is removed.
+    */
+    pub fn set_max_resting_orders(&mut self, max: Option<usize>) {
+        self.max_resting_orders = max;
+    }
+
+    pub fn set_replace_missing(&mut self, missing: ReplaceMissing) {
+        self.replace_missing = missing;
+    }
+
+    /*
+        Reject a cancel, or each uuid of a batch cancel, naming an order that
+        isn't on the book as UnknownOrder, rather than closing nothing. An
+        order that has already filled, expired or been cancelled counts as
+        unknown too, since the engine keeps nothing of it.
+    */
+    pub fn set_reject_unknown_cancels(&mut self, reject: bool) {
+        self.reject_unknown_cancels = reject;
+    }
+
+    /*
+        Sweep protection: a market order is rejected outright, rather than
+        part filled, unless the liquidity it could take right away covers at
+        least `ratio` of its amount (FOK is the same at a ratio of 1). The
+        liquidity counted is what matching would reach, so within any
+        slippage limit or price band. Only checked as the order is placed, so
+        a stop triggered later isn't held to it; orders at the mid aren't
+        market orders for this.
+    */
+    pub fn set_min_fill_ratio(&mut self, ratio: Option<N>) {
+        self.min_fill_ratio = ratio;
+    }
+
+    fn reject_unknown(&self, result: &mut MatchResult<N>, uuids: &[Uuid]) {
+        if !self.reject_unknown_cancels {
+            return;
+        }
+        for uuid in uuids {
+            if !result.closed.contains_key(uuid) {
+                result.reject(*uuid, RejectReason::UnknownOrder);
+            }
+        }
+    }
+
+    /*
+        Have each call report the price levels it changed, as deltas against
+        what was last reported. Turning this on takes the book as it stands
+        as the starting point, so a consumer mirroring the book should start
+        from depth() at that moment.
+    */
+    pub fn set_report_book_deltas(&mut self, report: bool) {
+        self.report_book_deltas = report;
+        self.reset_levels();
+    }
+
+    fn reset_levels(&mut self) {
+        self.touched.clear();
+        self.levels.clear();
+        if self.report_book_deltas {
+            for side in [Side::Buy, Side::Sell] {
+                for (price, size) in self.depth(side, usize::MAX) {
+                    self.levels.insert((side, price), size);
+                }
+            }
+        }
+    }
+
+    fn touch(&mut self, side: Side, price: N) {
+        if self.report_book_deltas {
+            self.touched.insert((side, price));
+        }
+    }
+
+    fn level_size(&self, side: Side, price: N) -> N {
+        let key = match side {
+            Side::Buy => -price,
+            Side::Sell => price,
+        };
+        self.book(side)
+            .range(PriceTime(key, 0, 0)..=PriceTime(key, u64::MAX, u64::MAX))
+            .map(|(_, order)| order.remaining_amount)
+            .sum()
+    }
+
+    // compares each touched level with what was last reported for it
+    fn level_deltas(&mut self) -> Vec<LevelDelta<N>> {
+        let mut deltas = Vec::new();
+        for (side, price) in mem::take(&mut self.touched) {
+            let size = self.level_size(side, price);
+            let change = match self.levels.get(&(side, price)) {
+                None if size.is_zero() => continue,
+                Some(_) if size.is_zero() => LevelChange::Removed,
+                Some(before) if *before == size => continue,
+                Some(_) => LevelChange::Changed,
+                None => LevelChange::Added,
+            };
+            if size.is_zero() {
+                self.levels.remove(&(side, price));
+            } else {
+                self.levels.insert((side, price), size);
+            }
+            deltas.push(LevelDelta {
+                side,
+                price,
+                size,
+                change,
+            });
+        }
+        deltas
+    }
+
+    /*
+        Seed the generator iceberg slices are jittered with, or with None
+        turn jitter off. The same seed and commands give the same slices.
+    */
+    pub fn set_jitter_seed(&mut self, seed: Option<u64>) {
+        self.rng = seed.map(Rng::new);
+    }
+
+    /*
+        Show an iceberg's next slice, jittered if it asks to be and there is
+        a seed. A jittered slice keeps to the scale of display_size and to
+        the lot size; one rounded away to nothing shows display_size.
+    */
+    fn show_slice(&mut self, order: &mut Order<N>) {
+        let (display_size, jitter, rng) =
+            match (order.display_size, order.display_jitter, &mut self.rng) {
+                (Some(display_size), Some(jitter), Some(rng)) => (display_size, jitter, rng),
+                _ => return order.show_slice(),
+            };
+        const SPAN: u32 = 1 << 16;
+        let draw = N::from(rng.below(2 * SPAN)) - N::from(SPAN); // in -SPAN..SPAN
+        let mut size = display_size + display_size * jitter * draw / N::from(SPAN);
+        size = size.truncate_to(display_size.scale());
+        if !self.lot_size.is_zero() {
+            size -= size % self.lot_size;
+        }
+        if size <= N::ZERO {
+            size = display_size;
+        }
+        order.show(size);
+    }
+
+    /*
+        With a timeout, an account placing cancel-on-disconnect orders has
+        to send a heartbeat (or place another such order) at least that
+        often. Once its deadline passes, the next call cancels all of its
+        cancel-on-disconnect orders before doing anything else. Without a
+        timeout the flag does nothing.
+    */
+    pub fn set_heartbeat_timeout(&mut self, timeout: Option<u64>) {
+        self.heartbeat_timeout = timeout;
+        if timeout.is_none() {
+            self.heartbeats.clear();
+        }
+    }
+
+    pub fn heartbeat(&mut self, account: u64, now: u64) {
+        if let Some(timeout) = self.heartbeat_timeout {
+            self.heartbeats.insert(account, now.saturating_add(timeout));
+        }
+    }
+
+    fn disconnect_due(&self, now: u64) -> bool {
+        self.heartbeats.values().any(|deadline| *deadline <= now)
+    }
+
+    // cancel the flagged orders of every account past its deadline
+    fn disconnect(&mut self, now: u64) -> MatchResult<N> {
+        let mut result = MatchResult::default();
+        if !self.disconnect_due(now) {
+            return result;
+        }
+        let mut quiet: Vec<u64> = self
+            .heartbeats
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(account, _)| *account)
+            .collect();
+        quiet.sort();
+        for account in quiet {
+            self.heartbeats.remove(&account);
+            for uuid in self.account_queue(account) {
+                if self
+                    .get(uuid)
+                    .is_some_and(|order| order.cancel_on_disconnect)
+                {
+                    self.remove(uuid);
+                    result.canceled(uuid, CloseReason::Disconnected);
+                }
+            }
+        }
+        result
+    }
+
+    fn book_full(&self, side: Side) -> bool {
+        self.max_resting_orders
+            .is_some_and(|max| self.book(side).len() >= max)
+    }
+
+    fn in_band(&self, price: N) -> bool {
+        match (self.reference_band, self.last_trade_price) {
+            (Some(band), Some(last)) => (price - last).abs() <= last.abs() * band,
+            _ => true,
+        }
+    }
+
+    // a market order gets the band edge (or its own limit, if tighter) as a limit
+    fn cap_to_band(&self, order: &mut Order<N>) {
+        if let (Some(band), Some(last)) = (self.reference_band, self.last_trade_price) {
+            let width = last.abs() * band;
+            order.price = Some(match (order.side, order.price) {
+                (Side::Buy, None) => last + width,
+                (Side::Sell, None) => last - width,
+                (Side::Buy, Some(price)) => cmp::min(price, last + width),
+                (Side::Sell, Some(price)) => cmp::max(price, last - width),
+            });
+        }
+    }
+
+    /*
+        For products such as spreads that can trade at or below zero.
+        Otherwise limit, stop and amended prices must be positive.
+    */
+    pub fn set_allow_negative_prices(&mut self, allow: bool) {
+        self.allow_negative_prices = allow;
+    }
+
+    fn valid_price(&self, price: N) -> bool {
+        self.allow_negative_prices || price > N::ZERO
+    }
+
+    pub fn set_price_tick(&mut self, tick: N) {
+        self.price_tick = tick;
+    }
+
+    /*
+        A mid that falls between ticks is rounded onto one before a mid peg
+        or mid market order uses it, Passive by default. Without a price
+        tick the mid is used exactly.
+    */
+    pub fn set_mid_rounding(&mut self, rounding: MidRounding) {
+        self.mid_rounding = rounding;
+    }
+
+    pub fn set_lot_size(&mut self, lot: N) {
+        self.lot_size = lot;
+    }
+
+    fn off_increment(&self, price: Option<N>, amount: N) -> Option<RejectReason> {
+        match price {
+            Some(price) if !multiple_of(price, self.price_tick) => Some(RejectReason::OffTick),
+            _ if !multiple_of(amount, self.lot_size) => Some(RejectReason::OffLot),
+            _ => None,
+        }
+    }
+
+    fn expiry(&self, order: &Order<N>) -> u64 {
+        match (order.tif, self.gtc_lifetime) {
+            (TimeInForce::GTC, Some(lifetime)) => order.created.saturating_add(lifetime),
+            _ => order.expiry(),
+        }
+    }
+
+    pub fn set_matching_policy(&mut self, policy: MatchingPolicy) {
+        self.matching_policy = policy;
+    }
+
+    pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    fn insert(&mut self, order: Order<N>) {
+        let time = order.created;
+        self.insert_at(order, time);
+    }
+
+    /*
+        `time` is when the order joined the queue at its price, which is
+        later than `created` for a refilled iceberg slice
+    */
+    fn insert_at(&mut self, order: Order<N>, time: u64) {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        self.insert_keyed(order, time, sequence);
+    }
+
+    // `sequence` must be unused by any other order; a woken order goes back under its own
+    fn insert_keyed(&mut self, order: Order<N>, time: u64, sequence: u64) {
+        /*
+            sort by price/time for SELL
+            sort by (-price)/time for BUY
+            which holds for negative prices too: a bid at -3 (key 3) is
+            ahead of one at -5 (key 5)
+        */
+        debug_assert!(
+            order.remaining_amount >= N::ZERO && order.hidden_amount >= N::ZERO,
+            "negative amount resting for {}",
+            order.uuid
+        );
+        let price = order.resting_price();
+        trace!(
+            "insert uuid={} side={} amount={} price={} sequence={}",
+            order.uuid,
+            order.side,
+            order.total_remaining(),
+            price,
+            sequence
+        );
+        if let Some(_uuid) = self
+            .uuid_to_side_price_time
+            .insert(order.uuid, SidePriceTime(order.side, price, time, sequence))
+        {
+            panic!("Duplicate UUID: {}", order.uuid);
+        }
+
+        let expiry = self.expiry(&order);
+        if expiry != NEVER {
+            self.expiry_uuid
+                .insert(TimeUuid(expiry, sequence, order.uuid));
+        }
+        self.index_account(order.account, order.uuid);
+        if order.peg.is_some() {
+            self.pegged.insert(order.uuid);
+        }
+
+        let side = order.side;
+        self.touch(side, price);
+        let key = match side {
+            Side::Buy => PriceTime(-price, time, sequence),
+            Side::Sell => PriceTime(price, time, sequence),
+        };
+        // the sequence number makes every key unique, whatever the timestamps
+        let replaced = self.resting(side).insert(key, order);
+        debug_assert!(replaced.is_none(), "Book key collision");
+
+        let best = self.best_key(side);
+        if best.is_none() || Some(key) < *best {
+            *best = Some(key);
+        }
+    }
+
+    fn index_account(&mut self, account: u64, uuid: Uuid) {
+        self.account_uuids.entry(account).or_default().insert(uuid);
+    }
+
+    fn unindex_account(&mut self, account: u64, uuid: Uuid) {
+        if let Some(uuids) = self.account_uuids.get_mut(&account) {
+            uuids.remove(&uuid);
+            if uuids.is_empty() {
+                self.account_uuids.remove(&account);
+            }
+        }
+    }
+
+    fn best_key(&mut self, side: Side) -> &mut Option<PriceTime<N>> {
+        match side {
+            Side::Buy => &mut self.best_buy,
+            Side::Sell => &mut self.best_sell,
+        }
+    }
+
+    /*
+        Checks that the indexes agree with the book: every resting order and
+        stop is indexed by uuid, and by account, under the key it is kept
+        at and nothing else is; the expiry index holds exactly the resting
+        orders that expire; the cached best keys are the first on each
+        side. Describes the first disagreement found. It walks everything,
+        so is for tests and debugging rather than for every call.
+    */
+    pub fn check_consistency(&self) -> Result<(), String> {
+        for (side, book, stops) in [
+            (Side::Buy, &self.buy, &self.buy_stops),
+            (Side::Sell, &self.sell, &self.sell_stops),
+        ] {
+            for (PriceTime(key, time, sequence), order) in book {
+                let price = match side {
+                    Side::Buy => -*key,
+                    Side::Sell => *key,
+                };
+                let expected = SidePriceTime(side, price, *time, *sequence);
+                if order.side != side
+                    || self.uuid_to_side_price_time.get(&order.uuid) != Some(&expected)
+                {
+                    return Err(format!("{} is misindexed by uuid", order.uuid));
+                }
+            }
+            for (PriceTime(key, time, sequence), stop) in stops {
+                // buy stops trigger from the lowest, so unlike the book it's sells that are negated
+                let stop_price = match side {
+                    Side::Buy => *key,
+                    Side::Sell => -*key,
+                };
+                let expected = SidePriceTime(side, stop_price, *time, *sequence);
+                if self.uuid_to_stop.get(&stop.order.uuid) != Some(&expected) {
+                    return Err(format!("stop {} is misindexed by uuid", stop.order.uuid));
+                }
+            }
+            let best = match side {
+                Side::Buy => self.best_buy,
+                Side::Sell => self.best_sell,
+            };
+            if best != book.keys().next().copied() {
+                return Err(format!("best {} out of date", side));
+            }
+        }
+        if self.uuid_to_side_price_time.len() != self.buy.len() + self.sell.len() {
+            return Err("uuid index has orders that aren't on the book".to_string());
+        }
+        if self.uuid_to_stop.len() != self.buy_stops.len() + self.sell_stops.len() {
+            return Err("stop index has stops that aren't waiting".to_string());
+        }
+        if self.expiry_uuid != self.expiry_index() {
+            return Err("expiry index doesn't match the book".to_string());
+        }
+        let mut by_account = 0;
+        for (account, uuids) in &self.account_uuids {
+            for uuid in uuids {
+                let order = self
+                    .get(*uuid)
+                    .or_else(|| self.get_stop(*uuid).map(|stop| &stop.order));
+                if order.map(|order| order.account) != Some(*account) {
+                    return Err(format!("{} is misindexed by account", uuid));
+                }
+            }
+            by_account += uuids.len();
+        }
+        if by_account != self.uuid_to_side_price_time.len() + self.uuid_to_stop.len() {
+            return Err("account index is missing orders".to_string());
+        }
+        if let Some(uuid) = self
+            .pegged
+            .iter()
+            .find(|uuid| self.get(**uuid).is_none_or(|order| order.peg.is_none()))
+        {
+            return Err(format!("{} isn't a resting pegged order", uuid));
+        }
+        Ok(())
+    }
+
+    // panics on check_consistency failing, in debug builds only
+    pub fn debug_assert_consistent(&self) {
+        if cfg!(debug_assertions) {
+            if let Err(message) = self.check_consistency() {
+                panic!("inconsistent engine: {}", message);
+            }
+        }
+    }
+
+    /*
+        The first order in the queue on `side`, without walking the book
+    */
+    fn top(&self, side: Side) -> Option<&Order<N>> {
+        let best = match side {
+            Side::Buy => self.best_buy,
+            Side::Sell => self.best_sell,
+        };
+        debug_assert!(
+            best == self.book(side).keys().next().copied(),
+            "best price cache out of date"
+        );
+        best.map(|key| &self.book(side)[&key])
+    }
+
+    pub fn place(&mut self, mut command: Place<N>, now: u64) -> MatchResult<N> {
+        /*
+            Prices are normalized on the way in, so that 100 and 100.00 are
+            one level and every fill there prints the same way
+        */
+        command.normalize_prices();
+        let stop_price = command.stop_price();
+        let max_slippage = command.max_slippage();
+        let at_mid = command.at_mid();
+        let notional = command.notional();
+        let mut order: Order<N> = Order::create(command, now);
+        if self.halted {
+            return rejection(order.uuid, RejectReason::Halted);
+        }
+        if self.uuid_to_side_price_time.contains_key(&order.uuid)
+            || self.uuid_to_stop.contains_key(&order.uuid)
+        {
+            let mut result = MatchResult::default();
+            result.reject(order.uuid, RejectReason::DuplicateUuid);
+            return result;
+        }
+        if let Some(reason) = order.validate() {
+            return rejection(order.uuid, reason);
+        }
+        if !self.lifetime_allowed(order.tif) {
+            return rejection(order.uuid, RejectReason::LifetimeTooLong);
+        }
+        if order.cancel_on_disconnect {
+            self.heartbeat(order.account, now);
+        }
+        // from here on a pegged order is checked at the price it starts at
+        if let Some(peg) = order.peg {
+            match self.peg_price(peg, order.side) {
+                Some(price) => order.price = Some(price),
+                None => return rejection(order.uuid, RejectReason::NoReference),
+            }
+        }
+        if !order
+            .price
+            .into_iter()
+            .chain(stop_price)
+            .all(|price| self.valid_price(price))
+        {
+            return rejection(order.uuid, RejectReason::InvalidPrice);
+        }
+        // a stop price and an iceberg's slice are held to the same increments
+        // (a notional amount is only turned into a base amount further down)
+        let increments = [
+            (order.price, if notional { N::ZERO } else { order.amount }),
+            (stop_price, order.display_size.unwrap_or(order.amount)),
+        ];
+        if let Some(reason) = increments
+            .iter()
+            .find_map(|(price, amount)| self.off_increment(*price, *amount))
+        {
+            return rejection(order.uuid, reason);
+        }
+
+        // a reduce-only order never trades more than the position it closes
+        if order.reduce_only {
+            let reducible = self.reducible(order.account, order.side);
+            if reducible.is_zero() {
+                return rejection(order.uuid, RejectReason::NoPosition);
+            }
+            if !notional {
+                order.amount = cmp::min(order.amount, reducible);
+                order.remaining_amount = order.amount;
+            }
+        }
+
+        let market = order.price.is_none();
+        /*
+            Protect a market order with a limit price set off the price it
+            would first trade at, which isn't the best if self-trade
+            prevention passes over that
+        */
+        if let Some(max_slippage) = max_slippage {
+            if max_slippage < N::ZERO {
+                return rejection(order.uuid, RejectReason::InvalidPrice);
+            }
+            order.price = self
+                .first_match_price(&order)
+                .map(|first| match order.side {
+                    Side::Buy => first + max_slippage,
+                    Side::Sell => first - max_slippage,
+                });
+        }
+        if at_mid {
+            let mid = Peg {
+                reference: PegRef::Mid,
+                offset: N::ZERO,
+            };
+            match self.peg_price(mid, order.side) {
+                Some(price) => order.price = Some(price),
+                None => return rejection(order.uuid, RejectReason::NoReference),
+            }
+        }
+
+        // priced orders, stop-limits included, are checked as they are placed
+        if !market && !self.in_band(order.resting_price()) {
+            return rejection(order.uuid, RejectReason::OutsideBand);
+        }
+
+        if let Some(stop_price) = stop_price {
+            // a stop that would trigger straight away is just placed
+            let triggered = self
+                .last_trade_price
+                .is_some_and(|last| stop_triggered(order.side, stop_price, last));
+            if !triggered {
+                self.insert_stop(order, stop_price);
+                return MatchResult::default();
+            }
+        }
+
+        if market {
+            self.cap_to_band(&mut order);
+        }
+
+        // an order that can't rest would just be cancelled in an auction
+        if self.auction
+            && (order.price.is_none() || matches!(order.tif, TimeInForce::IOC | TimeInForce::FOK))
+        {
+            return rejection(order.uuid, RejectReason::Auction);
+        }
+        if notional {
+            let mut base = self.notional_base(&order, order.amount);
+            if order.reduce_only {
+                base = cmp::min(base, self.reducible(order.account, order.side));
+            }
+            order.amount = base;
+            order.remaining_amount = base;
+            // too little to buy even one lot, so there's nothing to match
+            if base.is_zero() {
+                let mut result = MatchResult::default();
+                result.unfilled(&order);
+                return result;
+            }
+        }
+        // nothing to trade against at all, as opposed to nothing at its price
+        if market && !at_mid && self.book(other_side(order.side)).is_empty() {
+            let mut result = MatchResult::default();
+            result.unfilled_as(&order, CloseReason::NoLiquidity);
+            return result;
+        }
+        if let (true, false, Some(ratio)) = (market, at_mid, self.min_fill_ratio) {
+            if self.crossed_liquidity(&order) < ratio * order.remaining_amount {
+                return rejection(order.uuid, RejectReason::ThinBook);
+            }
+        }
+
+        let mut result = self.place_order(order);
+        result.append(self.trigger_stops(now));
+        result
+    }
+
+    /*
+        What `call` would return for placing `place` at `now`, worked out on
+        a throwaway copy of the engine so this one is left untouched. The
+        copy is rebuilt from a snapshot, so this costs a pass over the book.
+    */
+    pub fn simulate(&self, place: &Place<N>, now: u64) -> MatchResult<N> {
+        let mut copy = Engine::from_snapshot(self.snapshot());
+        let flushed = copy.flush(&now);
+        let mut result = copy.place(place.clone(), now);
+        result.append(copy.repeg(now));
+        merge(result, flushed)
+    }
+
+    // where a peg would put an order on `side` now
+    fn peg_price(&self, peg: Peg<N>, side: Side) -> Option<N> {
+        let best = |side| {
+            self.book(side)
+                .values()
+                .find(|order| order.peg.is_none())
+                .map(Order::resting_price)
+        };
+        let reference = match peg.reference {
+            PegRef::BestBid => best(Side::Buy)?,
+            PegRef::BestAsk => best(Side::Sell)?,
+            PegRef::Mid => {
+                let mid = (best(Side::Buy)? + best(Side::Sell)?) / N::from(2);
+                let up =
+                    match (self.mid_rounding, side) {
+                        (MidRounding::Passive, Side::Buy)
+                        | (MidRounding::Aggressive, Side::Sell) => false,
+                        (MidRounding::Passive, Side::Sell)
+                        | (MidRounding::Aggressive, Side::Buy) => true,
+                    };
+                round_to_step(mid, self.price_tick, up)
+            }
+        };
+        Some((reference + peg.offset).normalize())
+    }
+
+    /*
+        Move each pegged order whose peg has moved, in queue order. A move
+        that trades can shift the prices followed, and trigger stops that
+        do the same, so this goes round again until a pass doesn't trade.
+    */
+    fn repeg(&mut self, now: u64) -> MatchResult<N> {
+        let mut result = MatchResult::default();
+        if self.halted || self.auction {
+            return result;
+        }
+        loop {
+            let mut pegged: Vec<(u64, Uuid)> = self
+                .pegged
+                .iter()
+                .map(|uuid| (self.uuid_to_side_price_time[uuid].3, *uuid))
+                .collect();
+            pegged.sort();
+
+            let mut traded = false;
+            for (_, uuid) in pegged {
+                let (price, peg, side) = match self.get(uuid) {
+                    Some(order) => (
+                        order.price,
+                        order.peg.expect("Pegged order without a peg"),
+                        order.side,
+                    ),
+                    None => continue, // filled by an earlier move in this pass
+                };
+                let moved = match self.peg_price(peg, side) {
+                    Some(moved) if Some(moved) != price => moved,
+                    _ => continue,
+                };
+                if !self.valid_price(moved)
+                    || !multiple_of(moved, self.price_tick)
+                    || !self.in_band(moved)
+                {
+                    continue;
+                }
+
+                let mut order = self.take(uuid).expect("Data structure mismatch");
+                order.price = Some(moved);
+                let mut matched = self._match(&mut order);
+                if !matched.closed.contains_key(&order.uuid) {
+                    let side = order.side;
+                    self.insert_at(order, now);
+                    matched.append(self.wake_min_qty(other_side(side)));
+                }
+                traded |= !matched.fills.is_empty();
+                result.append(matched);
+                if self.fault.is_some() {
+                    return result;
+                }
+            }
+            if !traded {
+                return result;
+            }
+            result.append(self.trigger_stops(now));
+        }
+    }
+
+    fn insert_stop(&mut self, order: Order<N>, stop_price: N) {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        self.uuid_to_stop.insert(
+            order.uuid,
+            SidePriceTime(order.side, stop_price, order.created, sequence),
+        );
+        self.index_account(order.account, order.uuid);
+        let replaced = match order.side {
+            Side::Buy => self.buy_stops.insert(
+                PriceTime(stop_price, order.created, sequence),
+                StopOrder { order, stop_price },
+            ),
+            Side::Sell => self.sell_stops.insert(
+                PriceTime(-stop_price, order.created, sequence),
+                StopOrder { order, stop_price },
+            ),
+        };
+        debug_assert!(replaced.is_none(), "Stop key collision");
+    }
+
+    fn get_stop(&self, uuid: Uuid) -> Option<&StopOrder<N>> {
+        match self.uuid_to_stop.get(&uuid) {
+            Some(SidePriceTime(Side::Buy, stop_price, time, sequence)) => self
+                .buy_stops
+                .get(&PriceTime(*stop_price, *time, *sequence)),
+            Some(SidePriceTime(Side::Sell, stop_price, time, sequence)) => self
+                .sell_stops
+                .get(&PriceTime(-*stop_price, *time, *sequence)),
+            None => None,
+        }
+    }
+
+    fn take_stop(&mut self, uuid: Uuid) -> Option<StopOrder<N>> {
+        let stop = match self.uuid_to_stop.remove(&uuid) {
+            Some(SidePriceTime(Side::Buy, stop_price, time, sequence)) => self
+                .buy_stops
+                .remove(&PriceTime(stop_price, time, sequence)),
+            Some(SidePriceTime(Side::Sell, stop_price, time, sequence)) => self
+                .sell_stops
+                .remove(&PriceTime(-stop_price, time, sequence)),
+            None => None,
+        };
+        if let Some(stop) = &stop {
+            self.unindex_account(stop.order.account, uuid);
+        }
+        stop
+    }
+
+    /*
+        Place every stop the last trade price has reached, one at a time in
+        trigger order, so fills from one triggered stop can cascade into
+        triggering the next
+    */
+    fn trigger_stops(&mut self, now: u64) -> MatchResult<N> {
+        let mut result = MatchResult::default();
+        while let Some(uuid) = self.next_triggered_stop() {
+            if self.fault.is_some() {
+                break;
+            }
+            let stop = self.take_stop(uuid).expect("Data structure mismatch");
+            let mut order = stop.order;
+            order.created = now;
+            if order.price.is_none() {
+                self.cap_to_band(&mut order);
+            }
+            result.append(self.place_order(order));
+        }
+        result
+    }
+
+    fn next_triggered_stop(&self) -> Option<Uuid> {
+        let last_trade_price = self.last_trade_price?;
+        [
+            self.buy_stops.values().next(),
+            self.sell_stops.values().next(),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|stop| stop_triggered(stop.order.side, stop.stop_price, last_trade_price))
+        .map(|stop| stop.order.uuid)
+    }
+
+    fn place_order(&mut self, mut order: Order<N>) -> MatchResult<N> {
+        if order.post_only && self.would_cross(&order) {
+            return rejection(order.uuid, RejectReason::PostOnly);
+        }
+        if self.auction {
+            if self.book_full(order.side) {
+                return rejection(order.uuid, RejectReason::BookFull);
+            }
+            self.show_slice(&mut order);
+            self.insert(order);
+            return MatchResult::default();
+        }
+
+        let mut result: MatchResult<N> = self._match(&mut order);
+        // the command fails, so a taker that hit a fault isn't left resting
+        if self.fault.is_some() {
+            return result;
+        }
+
+        //add order to resting book if not immediately closed
+        if !result.closed.contains_key(&order.uuid) {
+            let side = order.side;
+            if self.book_full(side) {
+                if result.fills.is_empty() {
+                    return rejection(order.uuid, RejectReason::BookFull);
+                }
+                result.reject(order.uuid, RejectReason::BookFull);
+                result.unfilled(&order);
+                return result;
+            }
+            self.show_slice(&mut order);
+            self.insert(order);
+            result.append(self.wake_min_qty(other_side(side)));
+        }
+        result
+    }
+
+    /*
+        An order held back by its minimum quantity can rest crossing the
+        book. Each time liquidity is added against it, check whether there
+        is now enough and if so match it, keeping its place in the queue.
+    */
+    fn wake_min_qty(&mut self, side: Side) -> MatchResult<N> {
+        let mut result = MatchResult::default();
+        let waiting: Vec<Uuid> = self
+            .book(side)
+            .values()
+            .take_while(|order| self.would_cross(order))
+            .filter(|order| order.has_minimum())
+            .map(|order| order.uuid)
+            .collect();
+        for uuid in waiting {
+            if self.fault.is_some() {
+                break;
+            }
+            let ready = match self.get(uuid) {
+                Some(order) => self.crossed_liquidity(order) >= order.min_fill(),
+                None => false, // filled by an earlier order in this loop
+            };
+            if !ready {
+                continue;
+            }
+            let SidePriceTime(_, _, time, sequence) = self.uuid_to_side_price_time[&uuid];
+            let mut order = self.take(uuid).expect("Data structure mismatch");
+            let matched = self._match(&mut order);
+            if !matched.closed.contains_key(&order.uuid) {
+                self.insert_keyed(order, time, sequence);
+            }
+            result.append(matched);
+        }
+        result
+    }
+
+    pub fn cancel(&mut self, uuid: Uuid) -> BTreeSet<Uuid> {
+        self.cancel_returning(uuid)
+            .map(|order| BTreeSet::from([order.uuid]))
+            .unwrap_or_default()
+    }
+
+    /*
+        Cancel, handing back the order as it was when it was removed. A stop
+        that hasn't triggered yet is shown at its limit price, or at its stop
+        price if it would enter as a market order.
+    */
+    pub fn cancel_returning(&mut self, uuid: Uuid) -> Option<OrderView<N>> {
+        if let Some(order) = self.take(uuid) {
+            return Some(order.view());
+        }
+        self.take_stop(uuid).map(
+            |StopOrder {
+                 mut order,
+                 stop_price,
+             }| {
+                order.price = order.price.or(Some(stop_price));
+                order.view()
+            },
+        )
+    }
+
+    // cancel several orders at once, returning those that were there to cancel
+    pub fn cancel_batch(&mut self, uuids: &[Uuid]) -> BTreeSet<Uuid> {
+        self.cancel_batch_in_order(uuids).into_iter().collect()
+    }
+
+    fn cancel_batch_in_order(&mut self, uuids: &[Uuid]) -> Vec<Uuid> {
+        uuids
+            .iter()
+            .filter(|uuid| !self.cancel(**uuid).is_empty())
+            .copied()
+            .collect()
+    }
+
+    /*
+        Pull everything an account has on the book, including stops that
+        haven't triggered yet
+    */
+    pub fn cancel_account(&mut self, account: u64) -> BTreeSet<Uuid> {
+        self.cancel_account_in_order(account).into_iter().collect()
+    }
+
+    // oldest first, so the cancellations are reported in placing order
+    fn cancel_account_in_order(&mut self, account: u64) -> Vec<Uuid> {
+        let uuids = self.account_queue(account);
+        for uuid in &uuids {
+            self.cancel(*uuid);
+        }
+        uuids
+    }
+
+    /*
+        Cancel every resting order and waiting stop, oldest first, reporting
+        what each had left in `unfilled`. Afterwards the book and every
+        index into it are empty; the engine's clock, event sequence and
+        last trade price are kept. It goes on taking orders as before.
+    */
+    pub fn drain(&mut self) -> MatchResult<N> {
+        let mut queued: Vec<(u64, Uuid)> = self
+            .uuid_to_side_price_time
+            .iter()
+            .chain(&self.uuid_to_stop)
+            .map(|(uuid, SidePriceTime(_, _, _, sequence))| (*sequence, *uuid))
+            .collect();
+        queued.sort();
+        let mut result = MatchResult::default();
+        for (_, uuid) in queued {
+            if let Some(order) = self.cancel_returning(uuid) {
+                result.canceled(uuid, CloseReason::CanceledByUser);
+                result
+                    .unfilled
+                    .insert(uuid, order.remaining_amount + order.hidden_amount);
+            }
+        }
+        debug_assert!(
+            self.buy.is_empty() && self.sell.is_empty() && self.expiry_uuid.is_empty(),
+            "drain left orders behind"
+        );
+        result
+    }
+
+    // an account's orders and stops by when they were queued, not by uuid
+    fn account_queue(&self, account: u64) -> Vec<Uuid> {
+        let mut queued: Vec<(u64, Uuid)> = self
+            .account_uuids
+            .get(&account)
+            .into_iter()
+            .flatten()
+            .map(|uuid| {
+                let SidePriceTime(_, _, _, sequence) = self
+                    .uuid_to_side_price_time
+                    .get(uuid)
+                    .or_else(|| self.uuid_to_stop.get(uuid))
+                    .expect("Data structure mismatch");
+                (*sequence, *uuid)
+            })
+            .collect();
+        queued.sort();
+        queued.into_iter().map(|(_, uuid)| uuid).collect()
+    }
+
+    pub fn amend(&mut self, uuid: Uuid, new_price: N, new_amount: N, now: u64) -> MatchResult<N> {
+        /*
+            Reducing the amount at an unchanged price keeps time priority.
+            Anything else loses it: the order is taken off the book and
+            re-entered as if it were new at `now` (so a GTD lifetime also
+            restarts), which re-runs matching in case it now crosses.
+
+            For an iceberg `new_amount` is the new total, and a reduction
+            comes out of the hidden reserve first.
+        */
+        let mut result = MatchResult::default();
+        let new_price = new_price.normalize();
+
+        // amending down to nothing is just a cancel
+        if new_amount <= N::ZERO {
+            return cancellation(self.cancel(uuid));
+        }
+        if self.halted {
+            if self.get(uuid).is_some() {
+                result.reject(uuid, RejectReason::Halted);
+            }
+            return result;
+        }
+        // a bad price leaves the order as it was
+        if !self.valid_price(new_price) {
+            if self.get(uuid).is_some() {
+                result.reject(uuid, RejectReason::InvalidPrice);
+            }
+            return result;
+        }
+        if let Some(reason) = self.off_increment(Some(new_price), new_amount) {
+            if self.get(uuid).is_some() {
+                result.reject(uuid, reason);
+            }
+            return result;
+        }
+        if !self.in_band(new_price) {
+            if self.get(uuid).is_some() {
+                result.reject(uuid, RejectReason::OutsideBand);
+            }
+            return result;
+        }
+
+        match self.get_mut(uuid) {
+            Some(order) => {
+                if order.price == Some(new_price) && new_amount <= order.total_remaining() {
+                    order.amount -= order.total_remaining() - new_amount;
+                    order.remaining_amount = cmp::min(order.remaining_amount, new_amount);
+                    order.hidden_amount = new_amount - order.remaining_amount;
+                    return result;
+                }
+            }
+            None => return result,
+        }
+
+        let mut order = self.take(uuid).expect("Data structure mismatch");
+        order.amount += new_amount - order.total_remaining();
+        order.remaining_amount = new_amount;
+        order.hidden_amount = N::ZERO;
+        order.price = Some(new_price);
+        order.created = now;
+        let mut result = self.place_order(order);
+        result.append(self.trigger_stops(now));
+        result
+    }
+
+    /*
+        Cancel `cancel_uuid` and place `place` in one step, reported as the
+        cancel followed by whatever the placement did. The new order is
+        checked as usual after the cancel, so if it is rejected the old one
+        stays cancelled. It needs a uuid of its own: reusing the cancelled
+        one is rejected as DuplicateUuid and nothing is cancelled.
+    */
+    pub fn replace(&mut self, cancel_uuid: Uuid, place: Place<N>, now: u64) -> MatchResult<N> {
+        let uuid = place.uuid();
+        if uuid == cancel_uuid {
+            let mut result = MatchResult::default();
+            result.reject(uuid, RejectReason::DuplicateUuid);
+            return result;
+        }
+        let mut result = cancellation(self.cancel(cancel_uuid));
+        if result.closed.is_empty() && self.replace_missing == ReplaceMissing::Reject {
+            return rejection(uuid, RejectReason::UnknownOrder);
+        }
+        result.append(self.place(place, now));
+        result
+    }
+
+    pub fn reduce(&mut self, uuid: Uuid, amount: N) -> MatchResult<N> {
+        let mut result = MatchResult::default();
+        let total = match self.get(uuid) {
+            Some(order) => order.total_remaining(),
+            None => return result,
+        };
+        if amount <= N::ZERO {
+            result.reject(uuid, RejectReason::InvalidAmount);
+            return result;
+        }
+        if !multiple_of(amount, self.lot_size) {
+            result.reject(uuid, RejectReason::OffLot);
+            return result;
+        }
+        if amount >= total {
+            return cancellation(self.cancel(uuid));
+        }
+
+        // as with amend, an iceberg loses hidden quantity first
+        let order = self.get_mut(uuid).expect("Data structure mismatch");
+        order.amount -= amount;
+        order.remaining_amount = cmp::min(order.remaining_amount, total - amount);
+        order.hidden_amount = total - amount - order.remaining_amount;
+        result
+    }
+
+    pub fn get_order(&self, uuid: Uuid) -> Option<OrderView<N>> {
+        self.get(uuid).map(Order::view)
+    }
+
+    /*
+        Every resting order: buys best first, then sells best first, each
+        in queue order within a price
+    */
+    pub fn iter_orders(&self) -> impl Iterator<Item = OrderView<N>> + '_ {
+        self.buy.values().chain(self.sell.values()).map(Order::view)
+    }
+
+    /*
+        Resting orders on `side` priced from `low` to `high`, both ends
+        included, best price first and in queue order within a price. Empty
+        if low > high. Only the band is visited, not the whole book; buys are
+        keyed by negated price, so their band is [-high, -low].
+    */
+    pub fn orders_in_range(&self, side: Side, low: N, high: N) -> Vec<OrderView<N>> {
+        if low > high {
+            return Vec::new();
+        }
+        let (from, to) = match side {
+            Side::Buy => (-high, -low),
+            Side::Sell => (low, high),
+        };
+        self.book(side)
+            .range(PriceTime(from, 0, 0)..=PriceTime(to, u64::MAX, u64::MAX))
+            .map(|(_, order)| order.view())
+            .collect()
+    }
+
+    fn get(&self, uuid: Uuid) -> Option<&Order<N>> {
+        match self.uuid_to_side_price_time.get(&uuid) {
+            Some(SidePriceTime(Side::Buy, price, time, sequence)) => {
+                self.buy.get(&PriceTime(-*price, *time, *sequence))
+            }
+            Some(SidePriceTime(Side::Sell, price, time, sequence)) => {
+                self.sell.get(&PriceTime(*price, *time, *sequence))
+            }
+            None => None,
+        }
+    }
+
+    // taken to change the order, so its level is marked as touched
+    fn get_mut(&mut self, uuid: Uuid) -> Option<&mut Order<N>> {
+        let SidePriceTime(side, price, time, sequence) =
+            *self.uuid_to_side_price_time.get(&uuid)?;
+        self.touch(side, price);
+        match side {
+            Side::Buy => self.buy.get_mut(&PriceTime(-price, time, sequence)),
+            Side::Sell => self.sell.get_mut(&PriceTime(price, time, sequence)),
+        }
+    }
+
+    fn remove(&mut self, uuid: Uuid) -> bool {
+        self.take(uuid).is_some()
+    }
+
+    fn take(&mut self, uuid: Uuid) -> Option<Order<N>> {
+        /*
+            Remove from uuid_to_side_price_time, get (side, price, time)
+            Remove from self.buy/self.sell using (price,time)
+            Remove from expiry_uuid using the order's expiry
+        */
+        let result = self.uuid_to_side_price_time.remove(&uuid);
+
+        if let Some(SidePriceTime(side, price, time, sequence)) = result {
+            trace!("remove uuid={} side={} price={}", uuid, side, price);
+            self.touch(side, price);
+            let key = match side {
+                Side::Buy => PriceTime(-price, time, sequence),
+                Side::Sell => PriceTime(price, time, sequence),
+            };
+            let r = self.resting(side).remove(&key);
+            if *self.best_key(side) == Some(key) {
+                let next = self.book(side).keys().next().copied();
+                *self.best_key(side) = next;
+            }
+            if let Some(order) = r {
+                let expiry = self.expiry(&order);
+                if expiry != NEVER && !self.expiry_uuid.remove(&TimeUuid(expiry, sequence, uuid)) {
+                    panic!("expiry/uuid missing in expiry_uuid")
+                }
+                self.unindex_account(order.account, uuid);
+                self.pegged.remove(&uuid);
+                Some(order)
+            } else {
+                panic!("Data structure mismatch")
+            }
+        } else {
+            None
+        }
+    }
+    pub fn flush(&mut self, now: &u64) -> Vec<ExpiredOrder<N>> {
+        self.expire_while(|expiry| expiry <= *now)
+    }
+
+    fn flush_if(&mut self, flush: bool, now: u64) -> Vec<ExpiredOrder<N>> {
+        if flush {
+            self.flush(&now)
+        } else {
+            Vec::new()
+        }
+    }
+
+    // like flush, but leaves orders expiring exactly at `cutoff`
+    pub fn flush_before(&mut self, cutoff: u64) -> Vec<ExpiredOrder<N>> {
+        self.expire_while(|expiry| expiry < cutoff)
+    }
+
+    // the index is in expiry order, so this stops at the first order left
+    fn expire_while(&mut self, expired: impl Fn(u64) -> bool) -> Vec<ExpiredOrder<N>> {
+        let uuids: Vec<Uuid> = self
+            .expiry_uuid
+            .iter()
+            .take_while(|TimeUuid(expiry, _, _)| expired(*expiry))
+            .map(|TimeUuid(_, _, uuid)| *uuid)
+            .collect();
+        if !uuids.is_empty() {
+            debug!("flush expired={}", uuids.len());
+        }
+
+        // reported in expiry order
+        uuids
+            .into_iter()
+            .map(|uuid| {
+                let order = self.take(uuid).expect("Data structure mismatch");
+                ExpiredOrder {
+                    uuid,
+                    side: order.side,
+                    price: order.resting_price(),
+                    remaining_amount: order.total_remaining(),
+                    sequence: 0,
+                }
+            })
+            .collect()
+    }
+
+    pub fn call(
+        &mut self,
+        command_at_time: CommandAtTime<N>,
+    ) -> Result<MatchResult<N>, EngineError> {
+        /*
+            I think we should always flush before a place or a cancel
+
+            Commands must arrive in non-decreasing `now` order. Several
+            commands may share a tick; they are applied in arrival order, so
+            an earlier one has time priority over a later one at the same
+            price. A command older than the last tick is refused with
+            EngineError::OutOfOrder and leaves the engine untouched.
+        */
+        self.admit(&command_at_time)?;
+        self.apply(command_at_time, true)
+    }
+
+    // refuses a command from the past, otherwise logs it
+    fn admit(&mut self, command_at_time: &CommandAtTime<N>) -> Result<(), EngineError> {
+        if command_at_time.now < self.last_tick {
+            return Err(EngineError::OutOfOrder {
+                now: command_at_time.now,
+                last_tick: self.last_tick,
+            });
+        }
+        if let Some(log) = &mut self.command_log {
+            if let Err(e) = log.record(command_at_time) {
+                return Err(EngineError::CommandLog(e.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        The same as calling each command in turn, with one result each, but
+        without flushing before every command. Nothing can expire while the
+        clock stands still, except an order expiring in the tick it was
+        placed, so the book is flushed only when the front of the expiry
+        index is due: once at the start of each new tick in the usual case.
+    */
+    pub fn call_batch(
+        &mut self,
+        commands: Vec<CommandAtTime<N>>,
+    ) -> Vec<Result<MatchResult<N>, EngineError>> {
+        commands
+            .into_iter()
+            .map(|command_at_time| {
+                self.admit(&command_at_time)?;
+                let due = self
+                    .expiry_uuid
+                    .iter()
+                    .next()
+                    .is_some_and(|TimeUuid(expiry, _, _)| *expiry <= command_at_time.now)
+                    || self.disconnect_due(command_at_time.now);
+                self.apply(command_at_time, due)
+            })
+            .collect()
+    }
+
+    // `flush` false skips the flush before the command, which must then have nothing to expire
+    fn apply(
+        &mut self,
+        command_at_time: CommandAtTime<N>,
+        flush: bool,
+    ) -> Result<MatchResult<N>, EngineError> {
+        let now = command_at_time.now;
+        let command = command_at_time.command;
+
+        self.last_tick = now;
+        // accounts that have gone quiet lose their flagged orders before anything else
+        let mut result = if flush {
+            self.disconnect(now)
+        } else {
+            MatchResult::default()
+        };
+        result.append(match command {
+            Command::Place(place) => {
+                let flushed = self.flush_if(flush, now);
+                let result = self.place(place, now);
+                merge(result, flushed)
+            }
+            Command::Cancel(uuid) => {
+                let flushed = self.flush_if(flush, now);
+                let mut result = cancellation(self.cancel(uuid));
+                self.reject_unknown(&mut result, &[uuid]);
+                merge(result, flushed)
+            }
+            Command::CancelBatch(uuids) => {
+                let flushed = self.flush_if(flush, now);
+                let mut result = cancellation(self.cancel_batch_in_order(&uuids));
+                self.reject_unknown(&mut result, &uuids);
+                merge(result, flushed)
+            }
+            Command::CancelAccount(account) => {
+                let flushed = self.flush_if(flush, now);
+                let result = cancellation(self.cancel_account_in_order(account));
+                merge(result, flushed)
+            }
+            Command::Amend {
+                uuid,
+                new_price,
+                new_amount,
+            } => {
+                let flushed = self.flush_if(flush, now);
+                let result = self.amend(uuid, new_price, new_amount, now);
+                merge(result, flushed)
+            }
+            Command::Reduce { uuid, amount } => {
+                let flushed = self.flush_if(flush, now);
+                let result = self.reduce(uuid, amount);
+                merge(result, flushed)
+            }
+            Command::Replace { cancel_uuid, place } => {
+                let flushed = self.flush_if(flush, now);
+                let result = self.replace(cancel_uuid, place, now);
+                merge(result, flushed)
+            }
+            Command::Flush() => merge(MatchResult::default(), self.flush(&now)),
+            Command::DrainBook() => {
+                let flushed = self.flush_if(flush, now);
+                let result = self.drain();
+                merge(result, flushed)
+            }
+            Command::Auction() => {
+                self.set_auction_mode(true);
+                MatchResult::default()
+            }
+            Command::Cross() => {
+                let flushed = self.flush_if(flush, now);
+                let result = self.cross(now);
+                merge(result, flushed)
+            }
+            Command::Halt() => {
+                self.set_halted(true);
+                MatchResult::default()
+            }
+            Command::Resume() => {
+                self.set_halted(false);
+                MatchResult::default()
+            }
+            Command::Heartbeat(account) => {
+                self.heartbeat(account, now);
+                MatchResult::default()
+            }
+        });
+        if self.fault.is_none() {
+            result.append(self.repeg(now));
+        }
+        if self.report_book_deltas {
+            // matching changes makers in place, so their levels are found from the fills
+            for fill in &result.fills {
+                self.touch(other_side(fill.taker_side), fill.price);
+            }
+            result.book_deltas = self.level_deltas();
+        }
+        if let Some(message) = self.fault.take() {
+            return Err(EngineError::Invariant(message));
+        }
+        let result = self.number_events(result);
+        // recorded here rather than in _match so they carry their sequence
+        self.record_trades(&result.fills);
+        Ok(result)
+    }
+
+    // in canonical output order: expiries, fills, then closures as they happened
+    fn number_events(&mut self, mut result: MatchResult<N>) -> MatchResult<N> {
+        let mut next = || {
+            self.event_sequence += 1;
+            self.event_sequence
+        };
+        for expired in &mut result.expired {
+            expired.sequence = next();
+        }
+        for fill in &mut result.fills {
+            fill.sequence = next();
+        }
+        result.closed_sequence = result
+            .closures()
+            .into_iter()
+            .map(|uuid| (uuid, next()))
+            .collect();
+        result
+    }
+}
+/*
+Input/Output
+*/
+
+/*
+    `field` is the index of the offending comma-separated field in `line`
+    (0 is the timestamp, 1 the command name, ...), or for JSON input the
+    column at which the JSON parser gave up
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    MissingField {
+        line: String,
+        field: usize,
+    },
+    BadInteger {
+        line: String,
+        field: usize,
+    },
+    BadUuid {
+        line: String,
+        field: usize,
+    },
+    BadDecimal {
+        line: String,
+        field: usize,
+    },
+    BadLifetime {
+        line: String,
+        field: usize,
+    },
+    UnknownCommand {
+        line: String,
+        field: usize,
+    },
+    UnknownSide {
+        line: String,
+        field: usize,
+    },
+    UnknownTif {
+        line: String,
+        field: usize,
+    },
+    UnknownFlag {
+        line: String,
+        field: usize,
+    },
+    UnknownReference {
+        line: String,
+        field: usize,
+    },
+    // a time too large once it is scaled to nanoseconds
+    TimeOutOfRange {
+        line: String,
+        field: usize,
+    },
+    BadJson {
+        line: String,
+        field: usize,
+        message: String,
+    },
+}
+
+impl ParseError {
+    pub fn line(&self) -> &str {
+        match self {
+            ParseError::MissingField { line, .. }
+            | ParseError::BadInteger { line, .. }
+            | ParseError::BadUuid { line, .. }
+            | ParseError::BadDecimal { line, .. }
+            | ParseError::BadLifetime { line, .. }
+            | ParseError::UnknownCommand { line, .. }
+            | ParseError::UnknownSide { line, .. }
+            | ParseError::UnknownTif { line, .. }
+            | ParseError::UnknownFlag { line, .. }
+            | ParseError::UnknownReference { line, .. }
+            | ParseError::TimeOutOfRange { line, .. }
+            | ParseError::BadJson { line, .. } => line,
+        }
+    }
+
+    pub fn field(&self) -> usize {
+        match self {
+            ParseError::MissingField { field, .. }
+            | ParseError::BadInteger { field, .. }
+            | ParseError::BadUuid { field, .. }
+            | ParseError::BadDecimal { field, .. }
+            | ParseError::BadLifetime { field, .. }
+            | ParseError::UnknownCommand { field, .. }
+            | ParseError::UnknownSide { field, .. }
+            | ParseError::UnknownTif { field, .. }
+            | ParseError::UnknownFlag { field, .. }
+            | ParseError::UnknownReference { field, .. }
+            | ParseError::TimeOutOfRange { field, .. }
+            | ParseError::BadJson { field, .. } => *field,
+        }
+    }
+
+    // the same error found `by` fields into `line`
+    pub(crate) fn shifted(mut self, line: &str, by: usize) -> ParseError {
+        let (error_line, field) = match &mut self {
+            ParseError::MissingField { line, field }
+            | ParseError::BadInteger { line, field }
+            | ParseError::BadUuid { line, field }
+            | ParseError::BadDecimal { line, field }
+            | ParseError::BadLifetime { line, field }
+            | ParseError::UnknownCommand { line, field }
+            | ParseError::UnknownSide { line, field }
+            | ParseError::UnknownTif { line, field }
+            | ParseError::UnknownFlag { line, field }
+            | ParseError::UnknownReference { line, field }
+            | ParseError::TimeOutOfRange { line, field }
+            | ParseError::BadJson { line, field, .. } => (line, field),
+        };
+        *error_line = line.to_string();
+        *field += by;
+        self
+    }
+
+    fn description(&self) -> &str {
+        match self {
+            ParseError::MissingField { .. } => "missing field",
+            ParseError::BadInteger { .. } => "bad integer",
+            ParseError::BadUuid { .. } => "bad uuid",
+            ParseError::BadDecimal { .. } => "bad decimal",
+            ParseError::BadLifetime { .. } => "lifetime must be greater than zero",
+            ParseError::UnknownCommand { .. } => "unknown command",
+            ParseError::UnknownSide { .. } => "unknown side",
+            ParseError::UnknownTif { .. } => "unknown time in force",
+            ParseError::UnknownFlag { .. } => "unknown flag",
+            ParseError::UnknownReference { .. } => "unknown peg reference",
+            ParseError::TimeOutOfRange { .. } => "time out of range",
+            ParseError::BadJson { message, .. } => message,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at field {}: {}",
+            self.description(),
+            self.field(),
+            self.line()
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/*
+    The fields of one input line, plus enough context to build a ParseError.
+    The line is split once up front into slices of it, so reading a field
+    doesn't walk the line again; that costs one small Vec per line.
+*/
+struct Fields<'a> {
+    line: &'a str,
+    values: Vec<&'a str>,
+    unit: TimeUnit,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a str, unit: TimeUnit) -> Fields<'a> {
+        Fields {
+            line,
+            values: line.split(',').collect(),
+            unit,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn get(&self, field: usize) -> Result<&'a str, ParseError> {
+        match self.values.get(field) {
+            Some(value) => Ok(value),
+            None => Err(ParseError::MissingField {
+                line: self.line.to_string(),
+                field,
+            }),
+        }
+    }
+
+    fn integer(&self, field: usize) -> Result<u64, ParseError> {
+        u64::from_str(self.get(field)?).map_err(|_| ParseError::BadInteger {
+            line: self.line.to_string(),
+            field,
+        })
+    }
+
+    // an integer in the input's time unit, in nanoseconds
+    fn time(&self, field: usize) -> Result<u64, ParseError> {
+        self.integer(field)?
+            .checked_mul(self.unit.nanos())
+            .ok_or_else(|| ParseError::TimeOutOfRange {
+                line: self.line.to_string(),
+                field,
+            })
+    }
+
+    fn uuid(&self, field: usize) -> Result<Uuid, ParseError> {
+        Uuid::from_str(self.get(field)?).map_err(|_| ParseError::BadUuid {
+            line: self.line.to_string(),
+            field,
+        })
+    }
+
+    fn decimal<N: Number>(&self, field: usize) -> Result<N, ParseError> {
+        N::from_str(self.get(field)?).map_err(|_| ParseError::BadDecimal {
+            line: self.line.to_string(),
+            field,
+        })
+    }
+
+    fn side(&self, field: usize) -> Result<Side, ParseError> {
+        Side::from_str(self.get(field)?).map_err(|_| ParseError::UnknownSide {
+            line: self.line.to_string(),
+            field,
+        })
+    }
+
+    fn reference(&self, field: usize) -> Result<PegRef, ParseError> {
+        match self.get(field)? {
+            "best_bid" => Ok(PegRef::BestBid),
+            "best_ask" => Ok(PegRef::BestAsk),
+            "mid" => Ok(PegRef::Mid),
+            _ => Err(ParseError::UnknownReference {
+                line: self.line.to_string(),
+                field,
+            }),
+        }
+    }
+}
+
+/*
+    Returns the TIF and the index of the first field after it
+*/
+fn time_in_force(fields: &Fields, field: usize) -> Result<(TimeInForce, usize), ParseError> {
+    match fields.get(field)? {
+        "IOC" => Ok((TimeInForce::IOC, field + 1)),
+        "GTC" => Ok((TimeInForce::GTC, field + 1)),
+        "FOK" => Ok((TimeInForce::FOK, field + 1)),
+        "GTD" => {
+            /*lifetime probably has to be >0*/
+            let lifetime = fields.time(field + 1)?;
+            if lifetime < 1 {
+                return Err(ParseError::BadLifetime {
+                    line: fields.line.to_string(),
+                    field: field + 1,
+                });
+            }
+            Ok((TimeInForce::GTD(lifetime), field + 2))
+        }
+        "GTT" => Ok((TimeInForce::GTT(fields.time(field + 1)?), field + 2)),
+        _ => Err(ParseError::UnknownTif {
+            line: fields.line.to_string(),
+            field,
+        }),
+    }
+}
+
+// `at` is how far into the line the limit fields are shifted, as within a replace
+fn limit_order<N: Number>(fields: &Fields, at: usize) -> Result<Place<N>, ParseError> {
+    let (tif, flags) = time_in_force(fields, at + 7)?;
+
+    // anything after the TIF is an optional flag
+    let mut post_only = false;
+    let mut min_qty = None;
+    let mut reduce_only = false;
+    let mut aon = false;
+    let mut cancel_on_disconnect = false;
+    let mut max_price_range = None;
+    let mut field = flags;
+    while field < fields.len() {
+        match fields.get(field)? {
+            "post_only" => post_only = true,
+            "reduce_only" => reduce_only = true,
+            "aon" => aon = true,
+            "cancel_on_disconnect" => cancel_on_disconnect = true,
+            "min_qty" => {
+                field += 1;
+                min_qty = Some(fields.decimal(field)?);
+            }
+            "max_price_range" => {
+                field += 1;
+                max_price_range = Some(fields.decimal(field)?);
+            }
+            _ => {
+                return Err(ParseError::UnknownFlag {
+                    line: fields.line.to_string(),
+                    field,
+                })
+            }
+        }
+        field += 1;
+    }
+
+    Ok(Place::LimitOrder {
+        uuid: fields.uuid(at + 2)?,
+        account: fields.integer(at + 3)?,
+        side: fields.side(at + 4)?,
+        amount: fields.decimal(at + 5)?,
+        price: fields.decimal(at + 6)?,
+        tif,
+        post_only,
+        min_qty,
+        reduce_only,
+        aon,
+        cancel_on_disconnect,
+        max_price_range,
+    })
+}
+fn market_order_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    // optional max slippage, then optional reduce_only and notional flags
+    let mut max_slippage = None;
+    let mut reduce_only = false;
+    let mut notional = false;
+    for field in 6..fields.len() {
+        match fields.get(field)? {
+            "reduce_only" => reduce_only = true,
+            "notional" => notional = true,
+            _ if field == 6 => max_slippage = Some(fields.decimal(field)?),
+            _ => {
+                return Err(ParseError::UnknownFlag {
+                    line: fields.line.to_string(),
+                    field,
+                })
+            }
+        }
+    }
+    Ok(Command::Place(Place::MarketOrder {
+        uuid: fields.uuid(2)?,
+        account: fields.integer(3)?,
+        side: fields.side(4)?,
+        amount: fields.decimal(5)?,
+        max_slippage,
+        reduce_only,
+        notional,
+    }))
+}
+fn mid_market_order_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::Place(Place::MidMarketOrder {
+        uuid: fields.uuid(2)?,
+        account: fields.integer(3)?,
+        side: fields.side(4)?,
+        amount: fields.decimal(5)?,
+    }))
+}
+// the TIF may be followed by `jitter,<fraction>`
+fn iceberg_order_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    let (tif, flags) = time_in_force(fields, 8)?;
+    let display_jitter = match fields.get(flags) {
+        Ok("jitter") => Some(fields.decimal(flags + 1)?),
+        Ok(_) => {
+            return Err(ParseError::UnknownFlag {
+                line: fields.line.to_string(),
+                field: flags,
+            })
+        }
+        Err(_) => None,
+    };
+    Ok(Command::Place(Place::IcebergOrder {
+        uuid: fields.uuid(2)?,
+        account: fields.integer(3)?,
+        side: fields.side(4)?,
+        amount: fields.decimal(5)?,
+        price: fields.decimal(6)?,
+        display_size: fields.decimal(7)?,
+        tif,
+        display_jitter,
+    }))
+}
+fn stop_order_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::Place(Place::StopOrder {
+        uuid: fields.uuid(2)?,
+        account: fields.integer(3)?,
+        side: fields.side(4)?,
+        amount: fields.decimal(5)?,
+        stop_price: fields.decimal(6)?,
+    }))
+}
+fn stop_limit_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    let (tif, _) = time_in_force(fields, 8)?;
+    Ok(Command::Place(Place::StopLimit {
+        uuid: fields.uuid(2)?,
+        account: fields.integer(3)?,
+        side: fields.side(4)?,
+        amount: fields.decimal(5)?,
+        stop_price: fields.decimal(6)?,
+        limit_price: fields.decimal(7)?,
+        tif,
+    }))
+}
+// e.g. 5,peg,<uuid>,7,buy,2,best_bid,-0.5,GTC
+// e.g. 5,replace,<old uuid>,limit,<new uuid>,7,buy,2,101,GTC, with any limit flags after
+fn replace_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    let place = match fields.get(3)? {
+        "limit" => limit_order(fields, 2)?,
+        _ => {
+            return Err(ParseError::UnknownCommand {
+                line: fields.line.to_string(),
+                field: 3,
+            })
+        }
+    };
+    Ok(Command::Replace {
+        cancel_uuid: fields.uuid(2)?,
+        place,
+    })
+}
+fn pegged_order_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    let (tif, _) = time_in_force(fields, 8)?;
+    Ok(Command::Place(Place::PeggedOrder {
+        uuid: fields.uuid(2)?,
+        account: fields.integer(3)?,
+        side: fields.side(4)?,
+        amount: fields.decimal(5)?,
+        reference: fields.reference(6)?,
+        offset: fields.decimal(7)?,
+        tif,
+    }))
+}
+fn cancel_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::Cancel(fields.uuid(2)?))
+}
+// e.g. 5,cancel_batch,<uuid>,<uuid>,<uuid>
+fn cancel_batch_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    let uuids = (2..fields.len())
+        .map(|field| fields.uuid(field))
+        .collect::<Result<_, _>>()?;
+    Ok(Command::CancelBatch(uuids))
+}
+fn heartbeat_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::Heartbeat(fields.integer(2)?))
+}
+fn cancel_account_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::CancelAccount(fields.integer(2)?))
+}
+fn amend_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::Amend {
+        uuid: fields.uuid(2)?,
+        new_amount: fields.decimal(3)?,
+        new_price: fields.decimal(4)?,
+    })
+}
+fn reduce_command<N: Number>(fields: &Fields) -> Result<Command<N>, ParseError> {
+    Ok(Command::Reduce {
+        uuid: fields.uuid(2)?,
+        amount: fields.decimal(3)?,
+    })
+}
+pub fn parse_line<N: Number>(line: String) -> Result<CommandAtTime<N>, ParseError> {
+    parse_line_in(line, TimeUnit::Nanoseconds)
+}
+
+// `now`, GTD lifetimes and GTT expiries given in `unit` rather than nanoseconds
+pub fn parse_line_in<N: Number>(
+    line: String,
+    unit: TimeUnit,
+) -> Result<CommandAtTime<N>, ParseError> {
+    let fields = Fields::new(&line, unit);
+
+    let now = fields.time(0)?;
+
+    let command = match fields.get(1)? {
+        "flush" => Command::Flush(),
+        "halt" => Command::Halt(),
+        "resume" => Command::Resume(),
+        "auction" => Command::Auction(),
+        "cross" => Command::Cross(),
+        "drain" => Command::DrainBook(),
+        "limit" => Command::Place(limit_order(&fields, 0)?),
+        "market" => market_order_command(&fields)?,
+        "mid_market" => mid_market_order_command(&fields)?,
+        "iceberg" => iceberg_order_command(&fields)?,
+        "stop" => stop_order_command(&fields)?,
+        "stop_limit" => stop_limit_command(&fields)?,
+        "peg" => pegged_order_command(&fields)?,
+        "cancel" => cancel_command(&fields)?,
+        "cancel_batch" => cancel_batch_command(&fields)?,
+        "cancel_account" => cancel_account_command(&fields)?,
+        "heartbeat" => heartbeat_command(&fields)?,
+        "amend" => amend_command(&fields)?,
+        "reduce" => reduce_command(&fields)?,
+        "replace" => replace_command(&fields)?,
+        _ => {
+            return Err(ParseError::UnknownCommand {
+                line: line.to_string(),
+                field: 1,
+            })
+        }
+    };
+
+    Ok(CommandAtTime { now, command })
+}
+
+/*
+    JSON lines input, e.g.
+
+    {"now":1,"type":"limit","uuid":"...","account":7,"side":"buy","amount":"1.5","price":"100","tif":"GTC"}
+    {"now":2,"type":"limit",...,"tif":{"GTD":10},"post_only":true,"min_qty":"0.5"}
+    {"now":3,"type":"market","uuid":"...","account":7,"side":"sell","amount":"2","max_slippage":"5"}
+    {"now":3,"type":"market",...,"side":"buy","amount":"100","notional":true}
+    {"now":3,"type":"mid_market","uuid":"...","account":7,"side":"buy","amount":"2"}
+    {"now":3,"type":"iceberg",...,"price":"100","display_size":"1","tif":"GTC","display_jitter":"0.2"}
+    {"now":3,"type":"stop","uuid":"...","account":7,"side":"sell","amount":"2","stop_price":"95"}
+    {"now":3,"type":"stop_limit",...,"stop_price":"95","limit_price":"94","tif":"GTC"}
+    {"now":3,"type":"peg",...,"amount":"2","reference":"best_bid","offset":"-0.5","tif":"GTC"}
+    {"now":4,"type":"cancel","uuid":"..."}
+    {"now":4,"type":"cancel_batch","uuids":["...","..."]}
+    {"now":4,"type":"cancel_account","account":7}
+    {"now":4,"type":"heartbeat","account":7}
+    {"now":5,"type":"amend","uuid":"...","new_amount":"1","new_price":"101"}
+    {"now":6,"type":"reduce","uuid":"...","amount":"0.5"}
+    {"now":6,"type":"replace","cancel_uuid":"...","place":{"type":"limit","uuid":"...",...}}
+    {"now":7,"type":"flush"}
+    {"now":8,"type":"halt"}
+    {"now":9,"type":"resume"}
+    {"now":10,"type":"auction"}
+    {"now":11,"type":"cross"}
+    {"now":12,"type":"drain"}
+*/
+#[derive(Deserialize)]
+#[serde(bound = "N: Number")]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonCommand<N> {
+    Limit {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        price: N,
+        #[serde(deserialize_with = "json_time_in_force")]
+        tif: TimeInForce,
+        #[serde(default)]
+        post_only: bool,
+        #[serde(default)]
+        min_qty: Option<N>,
+        #[serde(default)]
+        reduce_only: bool,
+        #[serde(default)]
+        aon: bool,
+        #[serde(default)]
+        cancel_on_disconnect: bool,
+        #[serde(default)]
+        max_price_range: Option<N>,
+    },
+    Market {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        #[serde(default)]
+        max_slippage: Option<N>,
+        #[serde(default)]
+        reduce_only: bool,
+        #[serde(default)]
+        notional: bool,
+    },
+    MidMarket {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+    },
+    Iceberg {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        price: N,
+        display_size: N,
+        #[serde(deserialize_with = "json_time_in_force")]
+        tif: TimeInForce,
+        #[serde(default)]
+        display_jitter: Option<N>,
+    },
+    Stop {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        stop_price: N,
+    },
+    StopLimit {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        stop_price: N,
+        limit_price: N,
+        #[serde(deserialize_with = "json_time_in_force")]
+        tif: TimeInForce,
+    },
+    Peg {
+        uuid: Uuid,
+        account: u64,
+        side: Side,
+        amount: N,
+        reference: PegRef,
+        offset: N,
+        #[serde(deserialize_with = "json_time_in_force")]
+        tif: TimeInForce,
+    },
+    Cancel {
+        uuid: Uuid,
+    },
+    CancelBatch {
+        uuids: Vec<Uuid>,
+    },
+    CancelAccount {
+        account: u64,
+    },
+    Heartbeat {
+        account: u64,
+    },
+    Amend {
+        uuid: Uuid,
+        new_amount: N,
+        new_price: N,
+    },
+    Reduce {
+        uuid: Uuid,
+        amount: N,
+    },
+    Replace {
+        cancel_uuid: Uuid,
+        #[serde(deserialize_with = "json_place")]
+        place: Place<N>,
+    },
+    Flush,
+    Halt,
+    Resume,
+    Auction,
+    Cross,
+    Drain,
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "N: Number")]
+struct JsonLine<N> {
+    now: u64,
+    #[serde(flatten)]
+    command: JsonCommand<N>,
+}
+
+fn json_time_in_force<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TimeInForce, D::Error> {
+    match TimeInForce::deserialize(deserializer)? {
+        TimeInForce::GTD(0) => Err(serde::de::Error::custom(
+            "lifetime must be greater than zero",
+        )),
+        tif => Ok(tif),
+    }
+}
+
+// the order inside a replace, given as the same object as on its own line
+fn json_place<'de, D: Deserializer<'de>, N: Number>(deserializer: D) -> Result<Place<N>, D::Error> {
+    match json_command(JsonCommand::deserialize(deserializer)?) {
+        Command::Place(place) => Ok(place),
+        _ => Err(serde::de::Error::custom("replace must place an order")),
+    }
+}
+
+pub fn parse_json_line<N: Number>(line: String) -> Result<CommandAtTime<N>, ParseError> {
+    parse_json_line_in(line, TimeUnit::Nanoseconds)
+}
+
+// as parse_line_in; a bad time or lifetime found after parsing is reported at field 0
+pub fn parse_json_line_in<N: Number>(
+    line: String,
+    unit: TimeUnit,
+) -> Result<CommandAtTime<N>, ParseError> {
+    let parsed: JsonLine<N> = match serde_json::from_str(&line) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Err(ParseError::BadJson {
+                field: e.column(),
+                message: e.to_string(),
+                line,
+            })
+        }
+    };
+
+    let mut command = json_command(parsed.command);
+    let scale = |time: u64| time.checked_mul(unit.nanos());
+    let scaled = match &mut command {
+        Command::Place(place) | Command::Replace { place, .. } => match place.tif_mut() {
+            Some(TimeInForce::GTD(time)) => scale(*time).map(|nanos| *time = nanos),
+            Some(TimeInForce::GTT(time)) => scale(*time).map(|nanos| *time = nanos),
+            _ => Some(()),
+        },
+        _ => Some(()),
+    };
+    let now = match (scaled, scale(parsed.now)) {
+        (Some(()), Some(now)) => now,
+        _ => return Err(ParseError::TimeOutOfRange { line, field: 0 }),
+    };
+    Ok(CommandAtTime { now, command })
+}
+
+fn json_command<N: Number>(command: JsonCommand<N>) -> Command<N> {
+    match command {
+        JsonCommand::Limit {
+            uuid,
+            account,
+            side,
+            amount,
+            price,
+            tif,
+            post_only,
+            min_qty,
+            reduce_only,
+            aon,
+            cancel_on_disconnect,
+            max_price_range,
+        } => Command::Place(Place::LimitOrder {
+            uuid,
+            account,
+            side,
+            amount,
+            price,
+            tif,
+            post_only,
+            min_qty,
+            reduce_only,
+            aon,
+            cancel_on_disconnect,
+            max_price_range,
+        }),
+        JsonCommand::Market {
+            uuid,
+            account,
+            side,
+            amount,
+            max_slippage,
+            reduce_only,
+            notional,
+        } => Command::Place(Place::MarketOrder {
+            uuid,
+            account,
+            side,
+            amount,
+            max_slippage,
+            reduce_only,
+            notional,
+        }),
+        JsonCommand::MidMarket {
+            uuid,
+            account,
+            side,
+            amount,
+        } => Command::Place(Place::MidMarketOrder {
+            uuid,
+            account,
+            side,
+            amount,
+        }),
+        JsonCommand::Iceberg {
+            uuid,
+            account,
+            side,
+            amount,
+            price,
+            display_size,
+            tif,
+            display_jitter,
+        } => Command::Place(Place::IcebergOrder {
+            uuid,
+            account,
+            side,
+            amount,
+            price,
+            tif,
+            display_size,
+            display_jitter,
+        }),
+        JsonCommand::Stop {
+            uuid,
+            account,
+            side,
+            amount,
+            stop_price,
+        } => Command::Place(Place::StopOrder {
+            uuid,
+            account,
+            side,
+            amount,
+            stop_price,
+        }),
+        JsonCommand::StopLimit {
+            uuid,
+            account,
+            side,
+            amount,
+            stop_price,
+            limit_price,
+            tif,
+        } => Command::Place(Place::StopLimit {
+            uuid,
+            account,
+            side,
+            amount,
+            stop_price,
+            limit_price,
+            tif,
+        }),
+        JsonCommand::Peg {
+            uuid,
+            account,
+            side,
+            amount,
+            reference,
+            offset,
+            tif,
+        } => Command::Place(Place::PeggedOrder {
+            uuid,
+            account,
+            side,
+            amount,
+            reference,
+            offset,
+            tif,
+        }),
+        JsonCommand::Cancel { uuid } => Command::Cancel(uuid),
+        JsonCommand::CancelBatch { uuids } => Command::CancelBatch(uuids),
+        JsonCommand::CancelAccount { account } => Command::CancelAccount(account),
+        JsonCommand::Heartbeat { account } => Command::Heartbeat(account),
+        JsonCommand::Amend {
+            uuid,
+            new_amount,
+            new_price,
+        } => Command::Amend {
+            uuid,
+            new_price,
+            new_amount,
+        },
+        JsonCommand::Reduce { uuid, amount } => Command::Reduce { uuid, amount },
+        JsonCommand::Replace { cancel_uuid, place } => Command::Replace { cancel_uuid, place },
+        JsonCommand::Flush => Command::Flush(),
+        JsonCommand::Halt => Command::Halt(),
+        JsonCommand::Resume => Command::Resume(),
+        JsonCommand::Auction => Command::Auction(),
+        JsonCommand::Cross => Command::Cross(),
+        JsonCommand::Drain => Command::DrainBook(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn id(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    fn limit(n: u128, side: Side, amount: Decimal, price: Decimal) -> Place {
+        Place::LimitOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount,
+            tif: TimeInForce::GTC,
+            price,
+            post_only: false,
+            min_qty: None,
+            reduce_only: false,
+            aon: false,
+            cancel_on_disconnect: false,
+            max_price_range: None,
+        }
+    }
+
+    fn market(n: u128, side: Side, amount: Decimal) -> Place {
+        Place::MarketOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount,
+            max_slippage: None,
+            reduce_only: false,
+            notional: false,
+        }
+    }
+
+    // a market order spending `amount` of quote
+    fn notional(n: u128, side: Side, amount: Decimal) -> Place {
+        Place::MarketOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount,
+            max_slippage: None,
+            reduce_only: false,
+            notional: true,
+        }
+    }
+
+    fn mid_market(n: u128, side: Side, amount: Decimal) -> Place {
+        Place::MidMarketOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount,
+        }
+    }
+
+    fn iceberg(
+        n: u128,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+        display_size: Decimal,
+    ) -> Place {
+        Place::IcebergOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount,
+            price,
+            tif: TimeInForce::GTC,
+            display_size,
+            display_jitter: None,
+        }
+    }
+
+    fn stop(n: u128, side: Side, stop_price: Decimal) -> Place {
+        Place::StopOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount: dec!(1),
+            stop_price,
+        }
+    }
+
+    fn stop_limit(n: u128, side: Side, stop_price: Decimal, limit_price: Decimal) -> Place {
+        Place::StopLimit {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount: dec!(1),
+            stop_price,
+            limit_price,
+            tif: TimeInForce::GTC,
+        }
+    }
+
+    /*
+        The helpers above give the defaults; these change the one field a
+        test is about, e.g. limit(..).with_tif(TimeInForce::IOC). Each
+        panics on an order that doesn't have the field.
+    */
+    impl Place {
+        fn with_account(mut self, to: u64) -> Place {
+            match &mut self {
+                Place::LimitOrder { account, .. } | Place::MarketOrder { account, .. } => {
+                    *account = to
+                }
+                place => panic!("no account to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_tif(mut self, to: TimeInForce) -> Place {
+            match &mut self {
+                Place::LimitOrder { tif, .. } => *tif = to,
+                place => panic!("no tif to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_min_qty(mut self, to: Decimal) -> Place {
+            match &mut self {
+                Place::LimitOrder { min_qty, .. } => *min_qty = Some(to),
+                place => panic!("no min_qty to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_aon(mut self) -> Place {
+            match &mut self {
+                Place::LimitOrder { aon, .. } => *aon = true,
+                place => panic!("no aon to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_max_price_range(mut self, to: Decimal) -> Place {
+            match &mut self {
+                Place::LimitOrder {
+                    max_price_range, ..
+                } => *max_price_range = Some(to),
+                place => panic!("no max_price_range to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_reduce_only(mut self) -> Place {
+            match &mut self {
+                Place::LimitOrder { reduce_only, .. } | Place::MarketOrder { reduce_only, .. } => {
+                    *reduce_only = true
+                }
+                place => panic!("no reduce_only to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_cancel_on_disconnect(mut self) -> Place {
+            match &mut self {
+                Place::LimitOrder {
+                    cancel_on_disconnect,
+                    ..
+                } => *cancel_on_disconnect = true,
+                place => panic!("no cancel_on_disconnect to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_display_jitter(mut self, to: Decimal) -> Place {
+            match &mut self {
+                Place::IcebergOrder { display_jitter, .. } => *display_jitter = Some(to),
+                place => panic!("no display_jitter to set on {:?}", place),
+            }
+            self
+        }
+
+        fn with_max_slippage(mut self, to: Decimal) -> Place {
+            match &mut self {
+                Place::MarketOrder { max_slippage, .. } => *max_slippage = Some(to),
+                place => panic!("no max_slippage to set on {:?}", place),
+            }
+            self
+        }
+    }
+
+    fn call(engine: &mut Engine, now: u64, command: Command) -> MatchResult {
+        engine.call(CommandAtTime::new(now, command)).unwrap()
+    }
+
+    fn place(engine: &mut Engine, now: u64, place: Place) -> MatchResult {
+        call(engine, now, Command::Place(place))
+    }
+
+    fn filled(result: &MatchResult) -> Vec<(Uuid, Decimal, Decimal)> {
+        result
+            .fills
+            .iter()
+            .map(|fill| (fill.maker_uuid, fill.base_amount, fill.price))
+            .collect()
+    }
+
+    #[test]
+    fn corrupt_maker_amount_is_caught_before_going_negative() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(101)));
+        // as if some other bug had left the second ask all but used up
+        for maker in engine.resting(Side::Sell).values_mut() {
+            if maker.uuid == id(2) {
+                maker.remaining_amount = dec!(-0.0001);
+            }
+        }
+
+        let taker = limit(3, Side::Buy, dec!(5), dec!(101));
+        let error = engine
+            .call(CommandAtTime::new(2, Command::Place(taker)))
+            .unwrap_err();
+        assert!(matches!(error, EngineError::Invariant(_)));
+
+        // matching stopped at the bad order and the taker wasn't rested
+        assert!(engine.get_order(id(1)).is_none());
+        assert_eq!(
+            engine.get_order(id(2)).unwrap().remaining_amount,
+            dec!(-0.0001)
+        );
+        assert!(engine.get_order(id(3)).is_none());
+        assert_eq!(engine.best_bid(), None);
+    }
+
+    #[test]
+    fn notional_runs_out_part_way_into_a_level() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(10)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(3), dec!(11)));
+
+        let result = place(&mut engine, 2, notional(3, Side::Buy, dec!(42)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(2), dec!(10)), (id(2), dec!(2), dec!(11))]
+        );
+        assert_eq!(engine.best_ask(), Some((dec!(11), dec!(1))));
+    }
+
+    #[test]
+    fn notional_runs_out_at_a_level_boundary() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(10)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(3), dec!(11)));
+
+        let result = place(&mut engine, 2, notional(3, Side::Buy, dec!(20)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(2), dec!(10))]);
+        assert_eq!(engine.best_ask(), Some((dec!(11), dec!(3))));
+    }
+
+    #[test]
+    fn notional_that_does_not_divide_by_the_price_is_cut_to_the_amounts_scale() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(5.00), dec!(3)));
+
+        let result = place(&mut engine, 2, notional(2, Side::Buy, dec!(1)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(0.33), dec!(3))]);
+        assert_eq!(
+            engine.get_order(id(1)).unwrap().remaining_amount,
+            dec!(4.67)
+        );
+
+        // with a lot size it is cut down to whole lots instead
+        engine.set_lot_size(dec!(0.1));
+        let result = place(&mut engine, 3, notional(3, Side::Buy, dec!(1)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(0.3), dec!(3))]);
+    }
+
+    #[test]
+    fn notional_too_small_for_one_unit_trades_nothing() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(5), dec!(3)));
+
+        let result = place(&mut engine, 2, notional(2, Side::Buy, dec!(1)));
+        assert!(result.fills.is_empty());
+        assert_eq!(result.closed[&id(2)], CloseReason::IocCanceled);
+        assert_eq!(engine.best_ask(), Some((dec!(3), dec!(5))));
+    }
+
+    #[test]
+    fn drain_empties_both_sides_and_every_index() {
+        let mut engine = Engine::new();
+        engine.set_gtc_lifetime(Some(1_000));
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99)));
+        place(&mut engine, 2, limit(2, Side::Buy, dec!(2), dec!(98)));
+        place(&mut engine, 3, limit(3, Side::Sell, dec!(3), dec!(101)));
+        let stop = Place::StopOrder {
+            uuid: id(4),
+            account: 0,
+            side: Side::Sell,
+            amount: dec!(1),
+            stop_price: dec!(90),
+        };
+        place(&mut engine, 4, stop);
+
+        let result = call(&mut engine, 5, Command::DrainBook());
+        assert_eq!(
+            result.unfilled,
+            BTreeMap::from([
+                (id(1), dec!(1)),
+                (id(2), dec!(2)),
+                (id(3), dec!(3)),
+                (id(4), dec!(1)),
+            ])
+        );
+        assert!(engine.buy.is_empty() && engine.sell.is_empty());
+        assert!(engine.buy_stops.is_empty() && engine.sell_stops.is_empty());
+        assert!(engine.uuid_to_side_price_time.is_empty());
+        assert!(engine.uuid_to_stop.is_empty());
+        assert!(engine.expiry_uuid.is_empty());
+        assert_eq!(engine.last_tick, 5);
+        assert_eq!(engine.check_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn batch_cancel_line_parses_every_uuid() {
+        let uuids: Vec<String> = (1..=100).map(|n| id(n).to_string()).collect();
+        let line = format!("7,cancel_batch,{}", uuids.join(","));
+        let parsed: CommandAtTime = parse_line(line).unwrap();
+        assert_eq!(parsed.now, 7);
+        match parsed.command {
+            Command::CancelBatch(parsed) => {
+                assert_eq!(parsed, (1..=100).map(id).collect::<Vec<_>>())
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+
+        let line = format!("7,cancel_batch,{},oops", id(1));
+        assert_eq!(
+            parse_line::<Decimal>(line.clone()).unwrap_err(),
+            ParseError::BadUuid { line, field: 3 }
+        );
+    }
+
+    #[test]
+    fn best_price_cache_follows_inserts_cancels_and_fills() {
+        let mut engine = Engine::new();
+        let check = |engine: &Engine| {
+            assert_eq!(engine.check_consistency(), Ok(()));
+            for side in [Side::Buy, Side::Sell] {
+                assert_eq!(engine.best(side), engine.depth(side, 1).pop());
+            }
+        };
+
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(101)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(2), dec!(102)));
+        place(&mut engine, 1, limit(3, Side::Buy, dec!(1), dec!(99)));
+        check(&engine);
+        assert_eq!(engine.best_ask(), Some((dec!(101), dec!(1))));
+
+        // a better ask, then cancelling it puts the old best back
+        place(&mut engine, 2, limit(4, Side::Sell, dec!(1), dec!(100)));
+        check(&engine);
+        assert_eq!(engine.best_ask(), Some((dec!(100), dec!(1))));
+        call(&mut engine, 3, Command::Cancel(id(4)));
+        check(&engine);
+        assert_eq!(engine.best_ask(), Some((dec!(101), dec!(1))));
+
+        // a second order behind the best, then the level filled away
+        place(&mut engine, 4, limit(5, Side::Sell, dec!(1), dec!(101)));
+        check(&engine);
+        assert_eq!(engine.best_ask(), Some((dec!(101), dec!(2))));
+        place(&mut engine, 5, limit(6, Side::Buy, dec!(2.5), dec!(102)));
+        check(&engine);
+        assert_eq!(engine.best_ask(), Some((dec!(102), dec!(1.5))));
+
+        // the whole side emptied
+        place(&mut engine, 6, limit(7, Side::Buy, dec!(1.5), dec!(102)));
+        call(&mut engine, 7, Command::Cancel(id(3)));
+        check(&engine);
+        assert_eq!(engine.best_ask(), None);
+        assert_eq!(engine.best_bid(), None);
+    }
+
+    #[test]
+    fn gtd_lifetime_is_checked_against_the_configured_cap() {
+        let gtd = |n: u128, lifetime: u64| {
+            parse_line::<Decimal>(format!("1,limit,{},0,buy,1,99,GTD,{}", id(n), lifetime))
+                .unwrap()
+                .command
+        };
+        let mut engine = Engine::new();
+        let result = call(&mut engine, 1, gtd(1, MAX_LIFETIME));
+        assert!(result.rejected.is_empty());
+        let result = call(&mut engine, 1, gtd(2, MAX_LIFETIME + 1));
+        assert_eq!(result.rejected[&id(2)], RejectReason::LifetimeTooLong);
+
+        // the parser leaves the limit to the engine, so a longer cap is usable
+        engine.set_max_gtd_lifetime(Some(2 * MAX_LIFETIME));
+        let result = call(&mut engine, 1, gtd(3, MAX_LIFETIME + 1));
+        assert!(result.rejected.is_empty());
+        assert!(engine.get_order(id(3)).is_some());
+        let result = call(&mut engine, 1, gtd(4, 2 * MAX_LIFETIME + 1));
+        assert_eq!(result.rejected[&id(4)], RejectReason::LifetimeTooLong);
+    }
+
+    #[test]
+    fn slippage_limit_halts_a_market_order_walking_the_book() {
+        let mut engine = Engine::new();
+        engine.set_self_trade_prevention(SelfTradePrevention::SkipOwnOrders);
+        let ask = |n: u128, account: u64, price: Decimal| {
+            limit(n, Side::Sell, dec!(1), price).with_account(account)
+        };
+        // the account's own ask at the best is skipped, so slippage counts from 101
+        place(&mut engine, 1, ask(1, 7, dec!(100)));
+        place(&mut engine, 1, ask(2, 0, dec!(101)));
+        place(&mut engine, 1, ask(3, 0, dec!(102)));
+        place(&mut engine, 1, ask(4, 0, dec!(103)));
+        place(&mut engine, 1, ask(5, 0, dec!(104)));
+
+        let buy = market(6, Side::Buy, dec!(10))
+            .with_account(7)
+            .with_max_slippage(dec!(2));
+        let result = place(&mut engine, 2, buy);
+        assert_eq!(
+            filled(&result),
+            vec![
+                (id(2), dec!(1), dec!(101)),
+                (id(3), dec!(1), dec!(102)),
+                (id(4), dec!(1), dec!(103)),
+            ]
+        );
+        assert_eq!(result.closed[&id(6)], CloseReason::IocCanceled);
+        assert_eq!(result.unfilled[&id(6)], dec!(7));
+        assert!(engine.get_order(id(1)).is_some());
+        assert!(engine.get_order(id(5)).is_some());
+    }
+
+    #[test]
+    fn depth_sums_orders_at_the_same_price() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99)));
+        place(&mut engine, 1, limit(2, Side::Buy, dec!(2), dec!(99)));
+        place(&mut engine, 1, limit(3, Side::Buy, dec!(4), dec!(98)));
+        place(&mut engine, 1, limit(4, Side::Buy, dec!(8), dec!(97)));
+        place(&mut engine, 1, limit(5, Side::Sell, dec!(1.5), dec!(101)));
+        place(&mut engine, 1, limit(6, Side::Sell, dec!(0.5), dec!(101)));
+
+        assert_eq!(
+            engine.depth(Side::Buy, 2),
+            vec![(dec!(99), dec!(3)), (dec!(98), dec!(4))]
+        );
+        assert_eq!(engine.depth(Side::Sell, 5), vec![(dec!(101), dec!(2))]);
+        assert_eq!(engine.depth(Side::Sell, 0), vec![]);
+    }
+
+    #[test]
+    fn fees_are_basis_points_of_the_quote_amount() {
+        let mut engine = Engine::with_fees(dec!(-1), dec!(5));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(0.5), dec!(250.5)));
+
+        let result = place(&mut engine, 2, limit(3, Side::Buy, dec!(2.5), dec!(251)));
+        let fees: Vec<(Decimal, Decimal)> = result
+            .fills
+            .iter()
+            .map(|fill| (fill.maker_fee, fill.taker_fee))
+            .collect();
+        // 200 and 125.25 of quote; the maker is paid a rebate
+        assert_eq!(
+            fees,
+            vec![(dec!(-0.02), dec!(0.1)), (dec!(-0.012525), dec!(0.062625))]
+        );
+
+        let mut free = Engine::new();
+        place(&mut free, 1, limit(1, Side::Sell, dec!(3), dec!(7)));
+        let result = place(&mut free, 2, limit(2, Side::Buy, dec!(3), dec!(7)));
+        assert_eq!(result.fills[0].maker_fee, dec!(0));
+        assert_eq!(result.fills[0].taker_fee, dec!(0));
+    }
+
+    #[test]
+    fn duplicate_uuid_is_rejected_and_the_original_kept() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99)));
+
+        let result = place(&mut engine, 2, limit(1, Side::Sell, dec!(5), dec!(90)));
+        assert_eq!(result.rejected[&id(1)], RejectReason::DuplicateUuid);
+        assert!(result.fills.is_empty());
+        let original = engine.get_order(id(1)).unwrap();
+        assert_eq!(
+            (original.side, original.price, original.remaining_amount),
+            (Side::Buy, dec!(99), dec!(1))
+        );
+        assert_eq!(engine.best_ask(), None);
+    }
+
+    #[test]
+    fn pro_rata_shares_a_level_by_size_with_leftovers_to_the_oldest() {
+        let mut engine = Engine::new();
+        engine.set_matching_policy(MatchingPolicy::ProRata);
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 2, limit(2, Side::Sell, dec!(2), dec!(100)));
+        place(&mut engine, 3, limit(3, Side::Sell, dec!(3), dec!(100)));
+
+        // 4/6 of each, in whole units: 0, 1 and 2, with the unit left over to the oldest
+        let result = place(&mut engine, 4, limit(4, Side::Buy, dec!(4), dec!(100)));
+        assert_eq!(
+            filled(&result),
+            vec![
+                (id(1), dec!(1), dec!(100)),
+                (id(2), dec!(1), dec!(100)),
+                (id(3), dec!(2), dec!(100)),
+            ]
+        );
+        assert!(engine.get_order(id(1)).is_none());
+        assert_eq!(engine.get_order(id(2)).unwrap().remaining_amount, dec!(1));
+        assert_eq!(engine.get_order(id(3)).unwrap().remaining_amount, dec!(1));
+    }
+
+    #[test]
+    fn min_qty_short_of_liquidity_trades_nothing() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(101)));
+
+        let ioc = limit(3, Side::Buy, dec!(5), dec!(101))
+            .with_tif(TimeInForce::IOC)
+            .with_min_qty(dec!(3));
+        let result = place(&mut engine, 2, ioc);
+        assert!(result.fills.is_empty());
+        assert_eq!(result.closed[&id(3)], CloseReason::IocCanceled);
+
+        // a GTC order rests, crossing, until enough arrives to meet its minimum
+        let gtc = limit(4, Side::Buy, dec!(5), dec!(101))
+            .with_tif(TimeInForce::GTC)
+            .with_min_qty(dec!(3));
+        let result = place(&mut engine, 3, gtc);
+        assert!(result.fills.is_empty());
+        assert_eq!(engine.best_bid(), Some((dec!(101), dec!(5))));
+        let result = place(&mut engine, 4, limit(5, Side::Sell, dec!(1), dec!(101)));
+        assert_eq!(
+            filled(&result),
+            vec![
+                (id(1), dec!(1), dec!(100)),
+                (id(2), dec!(1), dec!(101)),
+                (id(5), dec!(1), dec!(101)),
+            ]
+        );
+        assert_eq!(engine.best_bid(), Some((dec!(101), dec!(2))));
+    }
+
+    #[test]
+    fn min_qty_exactly_met_trades() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(2), dec!(101)));
+
+        let ioc = limit(3, Side::Buy, dec!(5), dec!(101))
+            .with_tif(TimeInForce::IOC)
+            .with_min_qty(dec!(3));
+        let result = place(&mut engine, 2, ioc);
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(1), dec!(100)), (id(2), dec!(2), dec!(101))]
+        );
+        assert_eq!(result.unfilled[&id(3)], dec!(2));
+    }
+
+    #[test]
+    fn woken_min_qty_order_keeps_its_place_at_the_same_tick() {
+        let mut engine = Engine::new();
+        let waiting = |n| limit(n, Side::Buy, dec!(4), dec!(101)).with_min_qty(dec!(2));
+        place(&mut engine, 1, waiting(1));
+        place(&mut engine, 1, waiting(2));
+
+        // each sell is too small for either minimum, so rests crossing them
+        place(&mut engine, 1, limit(3, Side::Sell, dec!(1), dec!(101)));
+        let result = place(&mut engine, 1, limit(4, Side::Sell, dec!(1), dec!(101)));
+        // the second wakes the first buy, which goes back ahead of the other
+        assert_eq!(
+            filled(&result),
+            vec![(id(3), dec!(1), dec!(101)), (id(4), dec!(1), dec!(101))]
+        );
+        assert!(result.fills.iter().all(|fill| fill.taker_uuid == id(1)));
+        assert_eq!(engine.check_consistency(), Ok(()));
+
+        let result = place(&mut engine, 1, limit(5, Side::Sell, dec!(2), dec!(101)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(2), dec!(101))]);
+        assert_eq!(engine.get_order(id(2)).unwrap().remaining_amount, dec!(4));
+    }
+
+    #[test]
+    fn orders_off_the_tick_or_lot_are_rejected() {
+        let mut engine = Engine::new();
+        engine.set_price_tick(dec!(0.5));
+        engine.set_lot_size(dec!(0.1));
+
+        let result = place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99.25)));
+        assert_eq!(result.rejected[&id(1)], RejectReason::OffTick);
+        let result = place(&mut engine, 1, limit(2, Side::Buy, dec!(1.05), dec!(99.5)));
+        assert_eq!(result.rejected[&id(2)], RejectReason::OffLot);
+        let result = place(&mut engine, 1, market(3, Side::Sell, dec!(0.25)));
+        assert_eq!(result.rejected[&id(3)], RejectReason::OffLot);
+        assert_eq!(engine.best_bid(), None);
+
+        let result = place(&mut engine, 1, limit(4, Side::Buy, dec!(1.1), dec!(99.5)));
+        assert!(result.rejected.is_empty());
+        assert_eq!(engine.best_bid(), Some((dec!(99.5), dec!(1.1))));
+    }
+
+    #[test]
+    fn total_volume_and_imbalance() {
+        let mut engine = Engine::new();
+        assert_eq!(engine.total_volume(Side::Buy), dec!(0));
+        assert_eq!(engine.book_imbalance(), None);
+
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99)));
+        place(&mut engine, 1, limit(2, Side::Buy, dec!(2), dec!(98)));
+        place(&mut engine, 1, limit(3, Side::Sell, dec!(1), dec!(101)));
+        assert_eq!(engine.total_volume(Side::Buy), dec!(3));
+        assert_eq!(engine.total_volume(Side::Sell), dec!(1));
+        assert_eq!(engine.book_imbalance(), Some(dec!(0.5)));
+
+        call(&mut engine, 2, Command::Cancel(id(3)));
+        assert_eq!(engine.book_imbalance(), Some(dec!(1)));
+    }
+
+    #[test]
+    fn settlement_is_rounded_to_the_quote_precision() {
+        let mut engine = Engine::new();
+        engine.set_quote_precision(2, QuoteRounding::HalfEven);
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(0.125)));
+        let result = place(&mut engine, 2, market(2, Side::Buy, dec!(1)));
+        let fill = &result.fills[0];
+        assert_eq!(fill.quote_amount(), dec!(0.125));
+        assert_eq!(fill.settlement_amount, dec!(0.12));
+        assert_eq!(
+            fill.rounded_quote_amount(2, QuoteRounding::HalfUp),
+            dec!(0.13)
+        );
+
+        engine.set_quote_precision(8, QuoteRounding::HalfUp);
+        place(
+            &mut engine,
+            3,
+            limit(3, Side::Sell, dec!(0.000000025), dec!(1)),
+        );
+        let result = place(&mut engine, 4, market(4, Side::Buy, dec!(0.000000025)));
+        let fill = &result.fills[0];
+        assert_eq!(fill.settlement_amount, dec!(0.00000003));
+        assert_eq!(
+            fill.rounded_quote_amount(8, QuoteRounding::HalfEven),
+            dec!(0.00000002)
+        );
+    }
+
+    #[test]
+    fn halt_rejects_places_but_allows_cancels() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(101)));
+        call(&mut engine, 2, Command::Halt());
+
+        let result = place(&mut engine, 3, limit(3, Side::Buy, dec!(1), dec!(100)));
+        assert_eq!(result.rejected[&id(3)], RejectReason::Halted);
+        assert!(result.fills.is_empty());
+        let result = call(&mut engine, 4, Command::Cancel(id(2)));
+        assert_eq!(result.closed[&id(2)], CloseReason::CanceledByUser);
+
+        call(&mut engine, 5, Command::Resume());
+        let result = place(&mut engine, 6, limit(4, Side::Buy, dec!(1), dec!(100)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(1), dec!(100))]);
+    }
+
+    #[test]
+    fn auction_crosses_at_the_price_trading_the_most() {
+        let mut engine = Engine::new();
+        call(&mut engine, 1, Command::Auction());
+        place(&mut engine, 2, limit(1, Side::Buy, dec!(5), dec!(102)));
+        place(&mut engine, 2, limit(2, Side::Buy, dec!(3), dec!(101)));
+        place(&mut engine, 2, limit(3, Side::Buy, dec!(4), dec!(100)));
+        place(&mut engine, 2, limit(4, Side::Sell, dec!(2), dec!(99)));
+        place(&mut engine, 2, limit(5, Side::Sell, dec!(4), dec!(100)));
+        place(&mut engine, 2, limit(6, Side::Sell, dec!(6), dec!(101)));
+        assert!(engine.spread().unwrap() < dec!(0));
+
+        /*
+            volume at each price: 99 -> 2, 100 -> 6, 101 -> 8, 102 -> 5,
+            so everything trades at 101
+        */
+        let result = call(&mut engine, 3, Command::Cross());
+        let traded: Vec<(Decimal, Decimal)> = result
+            .fills
+            .iter()
+            .map(|fill| (fill.base_amount, fill.price))
+            .collect();
+        assert_eq!(
+            traded,
+            vec![
+                (dec!(2), dec!(101)),
+                (dec!(3), dec!(101)),
+                (dec!(1), dec!(101)),
+                (dec!(2), dec!(101)),
+            ]
+        );
+        assert_eq!(engine.best_bid(), Some((dec!(100), dec!(4))));
+        assert_eq!(engine.best_ask(), Some((dec!(101), dec!(4))));
+
+        // and matching is continuous again afterwards
+        let result = place(&mut engine, 4, limit(7, Side::Sell, dec!(1), dec!(100)));
+        assert_eq!(filled(&result), vec![(id(3), dec!(1), dec!(100))]);
+    }
+
+    #[test]
+    fn price_band_rejects_limits_and_stops_market_orders_at_its_edge() {
+        let mut engine = Engine::new();
+        engine.set_reference_band(Some(dec!(0.05)));
+        // no trade yet, so nothing to measure against
+        let result = place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(107)));
+        assert!(result.rejected.is_empty());
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(3, Side::Buy, dec!(1), dec!(100)));
+
+        let result = place(&mut engine, 2, limit(4, Side::Sell, dec!(1), dec!(106)));
+        assert_eq!(result.rejected[&id(4)], RejectReason::OutsideBand);
+        place(&mut engine, 2, limit(5, Side::Sell, dec!(1), dec!(101)));
+        place(&mut engine, 2, limit(6, Side::Sell, dec!(1), dec!(104)));
+
+        // 107 is past the band's edge at 105, so the rest is cancelled
+        let result = place(&mut engine, 3, market(7, Side::Buy, dec!(5)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(5), dec!(1), dec!(101)), (id(6), dec!(1), dec!(104))]
+        );
+        assert_eq!(result.unfilled[&id(7)], dec!(3));
+        assert_eq!(engine.best_ask(), Some((dec!(107), dec!(1))));
+    }
+
+    #[test]
+    fn price_band_checks_a_stop_limit_price_when_it_is_placed() {
+        let mut engine = Engine::new();
+        engine.set_reference_band(Some(dec!(0.05)));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Buy, dec!(1), dec!(100)));
+
+        // waiting to trigger, or triggered straight away, a limit of 200 is out
+        let result = place(
+            &mut engine,
+            2,
+            stop_limit(3, Side::Buy, dec!(101), dec!(200)),
+        );
+        assert_eq!(result.rejected[&id(3)], RejectReason::OutsideBand);
+        let result = place(
+            &mut engine,
+            2,
+            stop_limit(4, Side::Buy, dec!(99), dec!(200)),
+        );
+        assert_eq!(result.rejected[&id(4)], RejectReason::OutsideBand);
+        assert!(engine.buy_stops.is_empty());
+
+        // one inside the band waits as usual
+        let result = place(
+            &mut engine,
+            3,
+            stop_limit(5, Side::Buy, dec!(101), dec!(104)),
+        );
+        assert!(result.rejected.is_empty());
+        assert_eq!(engine.buy_stops.len(), 1);
+    }
+
+    #[test]
+    fn reduce_only_is_capped_to_the_position() {
+        let mut engine = Engine::new();
+        // account 1 ends up long 3
+        place(
+            &mut engine,
+            1,
+            limit(1, Side::Sell, dec!(3), dec!(100)).with_account(2),
+        );
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Buy, dec!(3), dec!(100)).with_account(1),
+        );
+        place(
+            &mut engine,
+            2,
+            limit(3, Side::Buy, dec!(10), dec!(99)).with_account(2),
+        );
+
+        // within the position it trades in full
+        let result = place(
+            &mut engine,
+            3,
+            market(4, Side::Sell, dec!(1))
+                .with_account(1)
+                .with_reduce_only(),
+        );
+        assert_eq!(filled(&result), vec![(id(3), dec!(1), dec!(99))]);
+        assert_eq!(engine.position(1).unwrap().net, dec!(2));
+
+        // beyond it, only the position is traded
+        let result = place(
+            &mut engine,
+            4,
+            market(5, Side::Sell, dec!(5))
+                .with_account(1)
+                .with_reduce_only(),
+        );
+        assert_eq!(filled(&result), vec![(id(3), dec!(2), dec!(99))]);
+        assert_eq!(engine.position(1).unwrap().net, dec!(0));
+        assert_eq!(engine.best_bid(), Some((dec!(99), dec!(7))));
+
+        // with nothing left to reduce it is rejected
+        let result = place(
+            &mut engine,
+            5,
+            market(6, Side::Sell, dec!(1))
+                .with_account(1)
+                .with_reduce_only(),
+        );
+        assert_eq!(result.rejected[&id(6)], RejectReason::NoPosition);
+        // and buying more would only add to a flat position
+        let result = place(
+            &mut engine,
+            5,
+            market(7, Side::Buy, dec!(1))
+                .with_account(1)
+                .with_reduce_only(),
+        );
+        assert_eq!(result.rejected[&id(7)], RejectReason::NoPosition);
+    }
+
+    #[test]
+    fn positions_build_and_reduce_with_a_weighted_entry() {
+        let mut engine = Engine::new();
+        let position = |engine: &Engine, account| {
+            let position = engine.position(account).unwrap();
+            (position.net, position.average_price)
+        };
+        assert!(engine.position(1).is_none());
+
+        place(
+            &mut engine,
+            1,
+            limit(1, Side::Sell, dec!(1), dec!(100)).with_account(2),
+        );
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Buy, dec!(1), dec!(100)).with_account(1),
+        );
+        place(
+            &mut engine,
+            2,
+            limit(3, Side::Sell, dec!(3), dec!(104)).with_account(2),
+        );
+        place(
+            &mut engine,
+            2,
+            limit(4, Side::Buy, dec!(3), dec!(104)).with_account(1),
+        );
+        assert_eq!(position(&engine, 1), (dec!(4), dec!(103)));
+        assert_eq!(position(&engine, 2), (dec!(-4), dec!(103)));
+
+        // selling some back leaves the entry price alone
+        place(
+            &mut engine,
+            3,
+            limit(5, Side::Buy, dec!(1), dec!(90)).with_account(2),
+        );
+        place(
+            &mut engine,
+            3,
+            limit(6, Side::Sell, dec!(1), dec!(90)).with_account(1),
+        );
+        assert_eq!(position(&engine, 1), (dec!(3), dec!(103)));
+
+        // and going through flat starts again at the new price
+        place(
+            &mut engine,
+            4,
+            limit(7, Side::Buy, dec!(5), dec!(95)).with_account(2),
+        );
+        place(
+            &mut engine,
+            4,
+            limit(8, Side::Sell, dec!(5), dec!(95)).with_account(1),
+        );
+        assert_eq!(position(&engine, 1), (dec!(-2), dec!(95)));
+        assert_eq!(position(&engine, 2), (dec!(2), dec!(95)));
+    }
+
+    #[test]
+    fn flush_before_leaves_orders_expiring_at_the_cutoff() {
+        let mut engine = Engine::new();
+        place(
+            &mut engine,
+            1,
+            limit(1, Side::Buy, dec!(1), dec!(99)).with_tif(TimeInForce::GTD(8)),
+        );
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Buy, dec!(1), dec!(98)).with_tif(TimeInForce::GTD(9)),
+        );
+        place(
+            &mut engine,
+            1,
+            limit(3, Side::Buy, dec!(1), dec!(97)).with_tif(TimeInForce::GTD(10)),
+        );
+
+        let expired: Vec<Uuid> = engine
+            .flush_before(10)
+            .iter()
+            .map(|order| order.uuid)
+            .collect();
+        assert_eq!(expired, vec![id(1)]);
+        let expired: Vec<Uuid> = engine.flush(&10).iter().map(|order| order.uuid).collect();
+        assert_eq!(expired, vec![id(2)]);
+        assert!(engine.get_order(id(3)).is_some());
+    }
+
+    #[test]
+    fn market_order_exhausting_the_book_reports_what_it_had_left() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(0.5), dec!(101)));
+
+        let result = place(&mut engine, 2, market(3, Side::Buy, dec!(4)));
+        assert_eq!(result.fills.len(), 2);
+        assert_eq!(result.closed[&id(3)], CloseReason::IocCanceled);
+        assert_eq!(result.unfilled[&id(3)], dec!(2.5));
+        assert!(matches!(
+            result.events().last(),
+            Some(Event::Canceled { uuid, unfilled: Some(unfilled) })
+                if *uuid == id(3) && *unfilled == dec!(2.5)
+        ));
+    }
+
+    #[test]
+    fn negative_prices_sort_and_cross() {
+        let mut engine = Engine::new();
+        let result = place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(-3)));
+        assert_eq!(result.rejected[&id(1)], RejectReason::InvalidPrice);
+
+        engine.set_allow_negative_prices(true);
+        place(&mut engine, 1, limit(2, Side::Buy, dec!(1), dec!(-5)));
+        place(&mut engine, 1, limit(3, Side::Buy, dec!(1), dec!(-3)));
+        place(&mut engine, 1, limit(4, Side::Buy, dec!(1), dec!(0)));
+        assert_eq!(
+            engine.depth(Side::Buy, 3),
+            vec![(dec!(0), dec!(1)), (dec!(-3), dec!(1)), (dec!(-5), dec!(1))]
+        );
+
+        // a sell at -4 takes the bids at 0 and -3, but not -5
+        let result = place(&mut engine, 2, limit(5, Side::Sell, dec!(3), dec!(-4)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(4), dec!(1), dec!(0)), (id(3), dec!(1), dec!(-3))]
+        );
+        assert_eq!(engine.best_ask(), Some((dec!(-4), dec!(1))));
+
+        // and a market sell goes all the way down
+        place(&mut engine, 3, limit(6, Side::Buy, dec!(1), dec!(-4.5)));
+        let result = place(&mut engine, 4, market(7, Side::Sell, dec!(1)));
+        assert_eq!(filled(&result), vec![(id(6), dec!(1), dec!(-4.5))]);
+        let result = place(&mut engine, 5, market(8, Side::Sell, dec!(1)));
+        assert_eq!(filled(&result), vec![(id(2), dec!(1), dec!(-5))]);
+    }
+
+    #[test]
+    fn same_price_same_tick_orders_both_rest_and_fill_in_arrival_order() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(2), dec!(100)));
+        assert_eq!(engine.best_ask(), Some((dec!(100), dec!(3))));
+
+        let result = place(&mut engine, 1, limit(3, Side::Buy, dec!(3), dec!(100)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(1), dec!(100)), (id(2), dec!(2), dec!(100))]
+        );
+        assert_eq!(engine.best_ask(), None);
+    }
+
+    #[test]
+    fn taker_skips_an_aon_maker_it_cannot_fill_and_trades_past_it() {
+        let mut engine = Engine::new();
+        place(
+            &mut engine,
+            1,
+            limit(1, Side::Sell, dec!(5), dec!(100)).with_aon(),
+        );
+        place(&mut engine, 2, limit(2, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 2, limit(3, Side::Sell, dec!(1), dec!(101)));
+
+        let result = place(&mut engine, 3, limit(4, Side::Buy, dec!(2), dec!(101)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(2), dec!(1), dec!(100)), (id(3), dec!(1), dec!(101))]
+        );
+        // still at the front of its level
+        assert_eq!(engine.best_ask(), Some((dec!(100), dec!(5))));
+
+        // and filled only when it can be filled completely
+        let result = place(&mut engine, 4, limit(5, Side::Buy, dec!(5), dec!(100)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(5), dec!(100))]);
+    }
+
+    #[test]
+    fn aon_taker_rests_until_it_can_fill_in_full() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(100)));
+
+        let result = place(
+            &mut engine,
+            2,
+            limit(2, Side::Buy, dec!(3), dec!(100)).with_aon(),
+        );
+        assert!(result.fills.is_empty());
+        assert_eq!(engine.best_bid(), Some((dec!(100), dec!(3))));
+
+        let result = place(&mut engine, 3, limit(3, Side::Sell, dec!(1), dec!(100)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(2), dec!(100)), (id(3), dec!(1), dec!(100))]
+        );
+        assert_eq!(engine.best_bid(), None);
+    }
+
+    #[test]
+    fn orders_sharing_an_expiry_all_expire_together() {
+        let mut engine = Engine::new();
+        let gtd = |n: u128, side: Side, price: Decimal, lifetime: u64| {
+            limit(n, side, dec!(1), price).with_tif(TimeInForce::GTD(lifetime))
+        };
+        // the same expiry, 10, from different placements and lifetimes
+        place(&mut engine, 1, gtd(1, Side::Buy, dec!(99), 9));
+        place(&mut engine, 1, gtd(2, Side::Buy, dec!(99), 9));
+        place(&mut engine, 4, gtd(3, Side::Sell, dec!(101), 6));
+        place(&mut engine, 5, gtd(4, Side::Sell, dec!(102), 5));
+        assert_eq!(engine.expiry_uuid.len(), 4);
+        // cancelling one leaves the others sharing its expiry indexed
+        call(&mut engine, 6, Command::Cancel(id(4)));
+        assert_eq!(engine.expiry_uuid.len(), 3);
+
+        let result = call(&mut engine, 10, Command::Flush());
+        let expired: Vec<Uuid> = result.expired.iter().map(|order| order.uuid).collect();
+        assert_eq!(expired, vec![id(1), id(2), id(3)]);
+        assert!(engine.expiry_uuid.is_empty());
+        assert_eq!((engine.best_bid(), engine.best_ask()), (None, None));
+    }
+
+    #[test]
+    fn peg_follows_a_moving_best_bid() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99)));
+        let peg = Place::PeggedOrder {
+            uuid: id(2),
+            account: 0,
+            side: Side::Buy,
+            amount: dec!(2),
+            reference: PegRef::BestBid,
+            offset: dec!(-0.5),
+            tif: TimeInForce::GTC,
+        };
+        place(&mut engine, 2, peg);
+        let peg_price = |engine: &Engine| engine.get_order(id(2)).unwrap().price;
+        assert_eq!(peg_price(&engine), dec!(98.5));
+
+        // up with a better bid, and back down when it goes
+        place(&mut engine, 3, limit(3, Side::Buy, dec!(1), dec!(100)));
+        assert_eq!(peg_price(&engine), dec!(99.5));
+        call(&mut engine, 4, Command::Cancel(id(3)));
+        assert_eq!(peg_price(&engine), dec!(98.5));
+
+        // with the bid it follows filled away it stays put
+        let result = place(&mut engine, 5, limit(4, Side::Sell, dec!(1), dec!(99)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(1), dec!(99))]);
+        assert_eq!(peg_price(&engine), dec!(98.5));
+        assert_eq!(engine.best_bid(), Some((dec!(98.5), dec!(2))));
+    }
+
+    #[test]
+    fn mid_market_order_trades_only_at_the_mid_or_better() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(98)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(102)));
+
+        // the mid is 100 and no ask is at or under it
+        let result = place(&mut engine, 2, mid_market(3, Side::Buy, dec!(1)));
+        assert!(result.fills.is_empty());
+        assert_eq!(result.unfilled[&id(3)], dec!(1));
+
+        // but a sell pegged to the mid is
+        let peg = Place::PeggedOrder {
+            uuid: id(4),
+            account: 0,
+            side: Side::Sell,
+            amount: dec!(1),
+            reference: PegRef::Mid,
+            offset: dec!(0),
+            tif: TimeInForce::GTC,
+        };
+        place(&mut engine, 3, peg);
+        let result = place(&mut engine, 4, mid_market(5, Side::Buy, dec!(2)));
+        assert_eq!(filled(&result), vec![(id(4), dec!(1), dec!(100))]);
+        assert_eq!(result.unfilled[&id(5)], dec!(1));
+        assert!(engine.get_order(id(2)).is_some());
+    }
+
+    #[test]
+    fn mid_market_order_on_a_one_sided_book_is_rejected() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(102)));
+        let result = place(&mut engine, 2, mid_market(2, Side::Buy, dec!(1)));
+        assert_eq!(result.rejected[&id(2)], RejectReason::NoReference);
+        assert!(engine.get_order(id(1)).is_some());
+    }
+
+    #[test]
+    fn output_follows_input_order_rather_than_uuids() {
+        let script = || {
+            let mut engine = Engine::new();
+            let mut output = Vec::new();
+            // uuids out of order with the input, all expiring together
+            for (now, n) in [(1, 9), (2, 3), (3, 5)] {
+                let order =
+                    limit(n, Side::Buy, dec!(1), dec!(99)).with_tif(TimeInForce::GTD(10 - now));
+                output.push(place(&mut engine, now, order));
+            }
+            for n in [8, 2, 6] {
+                output.push(place(
+                    &mut engine,
+                    4,
+                    limit(n, Side::Sell, dec!(1), dec!(101)).with_account(1),
+                ));
+            }
+            output.push(call(&mut engine, 5, Command::CancelAccount(1)));
+            output.push(call(&mut engine, 10, Command::Flush()));
+            output
+        };
+
+        let output = script();
+        let canceled: Vec<Uuid> = output[6]
+            .events()
+            .iter()
+            .map(|event| match event {
+                Event::Canceled { uuid, .. } => *uuid,
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(canceled, vec![id(8), id(2), id(6)]);
+        let expired: Vec<Uuid> = output[7].expired.iter().map(|order| order.uuid).collect();
+        assert_eq!(expired, vec![id(9), id(3), id(5)]);
+
+        let bytes = |output: &[MatchResult]| serde_json::to_vec(output).unwrap();
+        assert_eq!(bytes(&script()), bytes(&output));
+    }
+
+    #[test]
+    fn vwap_of_one_and_several_fills() {
+        let mut engine = Engine::new();
+        assert_eq!(MatchResult::<Decimal>::default().vwap(), None);
+
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(100)));
+        let result = place(&mut engine, 2, market(2, Side::Buy, dec!(1)));
+        assert_eq!(result.vwap(), Some(dec!(100)));
+
+        place(&mut engine, 3, limit(3, Side::Sell, dec!(1), dec!(101)));
+        place(&mut engine, 3, limit(4, Side::Sell, dec!(2), dec!(104)));
+        let result = place(&mut engine, 4, market(5, Side::Buy, dec!(4)));
+        // (100 + 101 + 2 * 104) / 4
+        assert_eq!(result.vwap(), Some(dec!(102.25)));
+    }
+
+    #[test]
+    fn no_empty_order_is_ever_left_observable() {
+        for policy in [MatchingPolicy::PriceTime, MatchingPolicy::ProRata] {
+            let mut engine = Engine::new();
+            engine.set_matching_policy(policy);
+            let commands = vec![
+                Command::Place(iceberg(1, Side::Sell, dec!(5), dec!(100), dec!(2))),
+                Command::Place(limit(2, Side::Sell, dec!(1), dec!(100))),
+                Command::Place(limit(3, Side::Sell, dec!(3), dec!(101))),
+                Command::Place(limit(4, Side::Buy, dec!(2), dec!(100))),
+                Command::Place(market(5, Side::Buy, dec!(3))),
+                Command::Place(limit(6, Side::Buy, dec!(4), dec!(101))),
+                Command::Place(market(7, Side::Buy, dec!(10))),
+            ];
+            for (now, command) in commands.into_iter().enumerate() {
+                let result = call(&mut engine, now as u64, command);
+                for uuid in result.closed.keys() {
+                    assert!(engine.get_order(*uuid).is_none());
+                }
+                for order in engine.iter_orders() {
+                    assert!(order.remaining_amount > dec!(0));
+                    assert_eq!(engine.get_order(order.uuid).unwrap().uuid, order.uuid);
+                }
+                for side in [Side::Buy, Side::Sell] {
+                    assert!(engine
+                        .depth(side, 10)
+                        .iter()
+                        .all(|(_, size)| *size > dec!(0)));
+                }
+            }
+            assert!(engine.iter_orders().next().is_none());
+        }
+    }
+
+    #[test]
+    fn lifo_fills_the_newest_order_at_a_price_but_keeps_price_priority() {
+        for (tie_break, first, second) in [(TieBreak::Fifo, 2, 3), (TieBreak::Lifo, 3, 2)] {
+            let mut engine = Engine::new();
+            engine.set_tie_break(tie_break);
+            place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(101)));
+            place(&mut engine, 2, limit(2, Side::Sell, dec!(1), dec!(100)));
+            place(&mut engine, 3, limit(3, Side::Sell, dec!(1), dec!(100)));
+
+            let result = place(&mut engine, 4, limit(4, Side::Buy, dec!(2.5), dec!(101)));
+            assert_eq!(
+                filled(&result),
+                vec![
+                    (id(first), dec!(1), dec!(100)),
+                    (id(second), dec!(1), dec!(100)),
+                    (id(1), dec!(0.5), dec!(101)),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn full_side_rejects_resting_orders_but_still_matches() {
+        let mut engine = Engine::new();
+        engine.set_max_resting_orders(Some(2));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(101)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(102)));
+        assert_eq!(engine.stats().resting_orders, 2);
+
+        let result = place(&mut engine, 2, limit(3, Side::Sell, dec!(1), dec!(103)));
+        assert_eq!(
+            result.rejected,
+            BTreeMap::from([(id(3), RejectReason::BookFull)])
+        );
+        assert_eq!(engine.stats().resting_orders, 2);
+
+        // the other side has its own allowance
+        place(&mut engine, 3, limit(4, Side::Buy, dec!(1), dec!(99)));
+        assert_eq!(engine.stats().resting_orders, 3);
+
+        // a crossing order on the full side still trades
+        let result = place(&mut engine, 4, limit(5, Side::Sell, dec!(1), dec!(99)));
+        assert_eq!(filled(&result), vec![(id(4), dec!(1), dec!(99))]);
+        assert!(result.rejected.is_empty());
+
+        // and one that would rest what it has left keeps its fills
+        place(&mut engine, 5, limit(6, Side::Buy, dec!(1), dec!(99)));
+        let result = place(&mut engine, 6, limit(7, Side::Sell, dec!(2), dec!(99)));
+        assert_eq!(filled(&result), vec![(id(6), dec!(1), dec!(99))]);
+        assert_eq!(
+            result.rejected,
+            BTreeMap::from([(id(7), RejectReason::BookFull)])
+        );
+        assert_eq!(result.unfilled, BTreeMap::from([(id(7), dec!(1))]));
+        assert_eq!(engine.stats().resting_orders, 2);
+    }
+
+    #[test]
+    fn expiries_past_the_end_of_time_saturate_instead_of_overflowing() {
+        let late = u64::MAX - 10;
+        let mut engine = Engine::new();
+        engine.set_max_gtd_lifetime(None);
+        engine.set_gtc_lifetime(Some(u64::MAX));
+        place(
+            &mut engine,
+            late,
+            limit(1, Side::Buy, dec!(1), dec!(99)).with_tif(TimeInForce::GTD(u64::MAX)),
+        );
+        place(&mut engine, late, limit(2, Side::Buy, dec!(1), dec!(98)));
+        place(
+            &mut engine,
+            late,
+            limit(3, Side::Buy, dec!(1), dec!(97)).with_tif(TimeInForce::GTD(5)),
+        );
+
+        // saturated expiries are NEVER, so only the short GTD is indexed
+        assert_eq!(engine.expiry_uuid.len(), 1);
+        assert_eq!(engine.check_consistency(), Ok(()));
+        let expired = engine.flush(&(u64::MAX - 1));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].uuid, id(3));
+        assert!(engine.flush(&u64::MAX).is_empty());
+        assert!(engine.get_order(id(1)).is_some());
+        assert!(engine.get_order(id(2)).is_some());
+    }
+
+    #[test]
+    fn book_deltas_add_change_and_remove_levels() {
+        let delta = |side, price, size, change| LevelDelta {
+            side,
+            price,
+            size,
+            change,
+        };
+        let mut engine = Engine::new();
+        engine.set_report_book_deltas(true);
+
+        let result = place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(101)));
+        assert_eq!(
+            result.book_deltas,
+            vec![delta(Side::Sell, dec!(101), dec!(2), LevelChange::Added)]
+        );
+        let result = place(&mut engine, 2, limit(2, Side::Sell, dec!(1), dec!(101)));
+        assert_eq!(
+            result.book_deltas,
+            vec![delta(Side::Sell, dec!(101), dec!(3), LevelChange::Changed)]
+        );
+
+        // filling the whole level removes it, and the rest of the taker adds one
+        let result = place(&mut engine, 3, limit(3, Side::Buy, dec!(4), dec!(101)));
+        assert_eq!(filled(&result).len(), 2);
+        assert_eq!(
+            result.book_deltas,
+            vec![
+                delta(Side::Buy, dec!(101), dec!(1), LevelChange::Added),
+                delta(Side::Sell, dec!(101), dec!(0), LevelChange::Removed),
+            ]
+        );
+
+        // a command that leaves the book as it was reports nothing
+        let result = place(&mut engine, 4, market(4, Side::Buy, dec!(1)));
+        assert!(result.book_deltas.is_empty());
+    }
+
+    #[test]
+    fn jittered_slices_repeat_for_the_same_seed_and_survive_a_snapshot() {
+        let iceberg =
+            iceberg(1, Side::Sell, dec!(100), dec!(100), dec!(10)).with_display_jitter(dec!(0.5));
+        // the visible slice each time, each taken whole by a market buy
+        let slices = |seed: Option<u64>, restart_after: usize| {
+            let mut engine = Engine::new();
+            engine.set_jitter_seed(seed);
+            place(&mut engine, 1, iceberg.clone());
+            let mut slices = Vec::new();
+            while let Some((_, size)) = engine.best_ask() {
+                slices.push(size);
+                let n = slices.len() as u128 + 1;
+                place(&mut engine, n as u64, market(n, Side::Buy, size));
+                if slices.len() == restart_after {
+                    engine = Engine::restore(engine.snapshot());
+                }
+            }
+            slices
+        };
+
+        let jittered = slices(Some(7), usize::MAX);
+        assert_eq!(jittered.iter().sum::<Decimal>(), dec!(100));
+        assert!(jittered.iter().any(|&size| size != dec!(10)));
+        assert!(jittered[..jittered.len() - 1]
+            .iter()
+            .all(|&size| dec!(5) <= size && size <= dec!(15)));
+        assert_eq!(slices(Some(7), usize::MAX), jittered);
+        assert_eq!(slices(Some(7), 3), jittered);
+        assert_ne!(slices(Some(8), usize::MAX), jittered);
+
+        // without a seed every slice is display_size
+        assert_eq!(slices(None, usize::MAX), vec![dec!(10); 10]);
+    }
+
+    #[test]
+    fn aggregated_merges_fills_at_a_price_and_keeps_the_makers() {
+        let mut engine = Engine::new();
+        for n in 1..=3 {
+            place(
+                &mut engine,
+                1,
+                limit(n, Side::Sell, Decimal::from(n), dec!(100)),
+            );
+        }
+        place(&mut engine, 1, limit(4, Side::Sell, dec!(1), dec!(101)));
+        place(&mut engine, 1, limit(5, Side::Sell, dec!(2), dec!(101)));
+
+        let result = place(&mut engine, 2, market(6, Side::Buy, dec!(8)));
+        let prints = result.aggregated();
+        let summary: Vec<(Decimal, Decimal, Vec<Uuid>)> = prints
+            .iter()
+            .map(|print| {
+                let makers = print.fills.iter().map(|fill| fill.maker_uuid).collect();
+                (print.price, print.base_amount, makers)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (dec!(100), dec!(6), vec![id(1), id(2), id(3)]),
+                (dec!(101), dec!(2), vec![id(4), id(5)]),
+            ]
+        );
+        assert!(prints.iter().all(|print| print.taker_uuid == id(6)));
+        // the raw fills are untouched
+        assert_eq!(result.fills.len(), 5);
+        assert_eq!(filled(&result)[4], (id(5), dec!(1), dec!(101)));
+    }
+
+    #[test]
+    fn available_liquidity_up_to_a_price_limit() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(101)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(2), dec!(101)));
+        place(&mut engine, 1, limit(3, Side::Sell, dec!(4), dec!(103)));
+        place(&mut engine, 1, limit(4, Side::Buy, dec!(3), dec!(99)));
+        place(&mut engine, 1, limit(5, Side::Buy, dec!(5), dec!(97)));
+
+        // none, some and all of the asks
+        assert_eq!(engine.available_liquidity(Side::Sell, dec!(100)), dec!(0));
+        assert_eq!(engine.available_liquidity(Side::Sell, dec!(101)), dec!(3));
+        assert_eq!(engine.available_liquidity(Side::Sell, dec!(102)), dec!(3));
+        assert_eq!(engine.available_liquidity(Side::Sell, dec!(1000)), dec!(7));
+
+        // for the bids better means higher
+        assert_eq!(engine.available_liquidity(Side::Buy, dec!(100)), dec!(0));
+        assert_eq!(engine.available_liquidity(Side::Buy, dec!(98)), dec!(3));
+        assert_eq!(engine.available_liquidity(Side::Buy, dec!(0)), dec!(8));
+    }
+
+    #[test]
+    fn orders_in_range_includes_both_ends_on_either_side() {
+        let mut engine = Engine::new();
+        for (n, price) in [
+            (1, dec!(101)),
+            (2, dec!(102)),
+            (3, dec!(102)),
+            (4, dec!(105)),
+        ] {
+            place(&mut engine, 1, limit(n, Side::Sell, dec!(1), price));
+        }
+        for (n, price) in [(5, dec!(99)), (6, dec!(98)), (7, dec!(96))] {
+            place(&mut engine, 1, limit(n, Side::Buy, dec!(1), price));
+        }
+        let uuids = |side, low, high| -> Vec<Uuid> {
+            engine
+                .orders_in_range(side, low, high)
+                .iter()
+                .map(|order| order.uuid)
+                .collect()
+        };
+
+        // a band running past the best level, with an end on a level
+        assert_eq!(
+            uuids(Side::Sell, dec!(100), dec!(102)),
+            vec![id(1), id(2), id(3)]
+        );
+        assert_eq!(uuids(Side::Sell, dec!(102), dec!(104)), vec![id(2), id(3)]);
+        assert_eq!(uuids(Side::Sell, dec!(103), dec!(104)), vec![]);
+        // bids come best, that is highest, first
+        assert_eq!(uuids(Side::Buy, dec!(96), dec!(98.5)), vec![id(6), id(7)]);
+        assert_eq!(uuids(Side::Buy, dec!(97), dec!(100)), vec![id(5), id(6)]);
+        assert_eq!(uuids(Side::Buy, dec!(99), dec!(96)), vec![]);
+    }
+
+    #[test]
+    fn equal_prices_at_different_scales_share_one_level() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(2), dec!(100.00)));
+        place(&mut engine, 1, limit(3, Side::Sell, dec!(1), dec!(100.50)));
+
+        let depth = engine.depth(Side::Sell, 10);
+        assert_eq!(depth, vec![(dec!(100), dec!(3)), (dec!(100.5), dec!(1))]);
+        assert_eq!(depth[0].0.to_string(), "100");
+        assert_eq!(depth[1].0.to_string(), "100.5");
+
+        // both makers print at the same price, byte for byte
+        let result = place(&mut engine, 2, limit(4, Side::Buy, dec!(3), dec!(100.000)));
+        let prices: Vec<String> = result
+            .fills
+            .iter()
+            .map(|fill| fill.price.to_string())
+            .collect();
+        assert_eq!(prices, ["100", "100"]);
+    }
+
+    #[test]
+    fn largest_orders_by_visible_size_with_ties_in_book_order() {
+        let mut engine = Engine::new();
+        let sizes = [dec!(3), dec!(7), dec!(1), dec!(5), dec!(7), dec!(2)];
+        for (n, size) in (1..).zip(sizes) {
+            let price = dec!(100) + Decimal::from(n);
+            place(&mut engine, 1, limit(n, Side::Sell, size, price));
+        }
+        let largest = |n| -> Vec<(Uuid, Decimal)> {
+            engine
+                .largest_orders(Side::Sell, n)
+                .iter()
+                .map(|order| (order.uuid, order.remaining_amount))
+                .collect()
+        };
+
+        assert_eq!(largest(0), vec![]);
+        assert_eq!(
+            largest(3),
+            vec![(id(2), dec!(7)), (id(5), dec!(7)), (id(4), dec!(5))]
+        );
+        assert_eq!(largest(10).len(), 6);
+        assert_eq!(largest(10)[5], (id(3), dec!(1)));
+        assert!(engine.largest_orders(Side::Buy, 3).is_empty());
+    }
+
+    #[test]
+    fn thin_book_rejects_a_market_order_it_covers_too_little_of() {
+        let mut engine = Engine::new();
+        engine.set_min_fill_ratio(Some(dec!(0.5)));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(3), dec!(101)));
+
+        // 4 of 10 is 40%, short of the 50% asked for: nothing trades
+        let result = place(&mut engine, 2, market(3, Side::Buy, dec!(10)));
+        assert!(result.fills.is_empty());
+        assert_eq!(
+            result.rejected,
+            BTreeMap::from([(id(3), RejectReason::ThinBook)])
+        );
+        assert_eq!(engine.available_liquidity(Side::Sell, dec!(101)), dec!(4));
+
+        // 4 of 8 is exactly enough
+        let result = place(&mut engine, 3, market(4, Side::Buy, dec!(8)));
+        assert!(result.rejected.is_empty());
+        assert_eq!(result.fills.len(), 2);
+        assert_eq!(result.unfilled, BTreeMap::from([(id(4), dec!(4))]));
+
+        // limit orders aren't held to it
+        let result = place(&mut engine, 4, limit(5, Side::Buy, dec!(10), dec!(100)));
+        assert!(result.rejected.is_empty());
+    }
+
+    #[test]
+    fn market_order_against_an_empty_side_closes_as_no_liquidity() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(99)));
+
+        let result = place(&mut engine, 2, market(2, Side::Buy, dec!(3)));
+        assert!(result.fills.is_empty());
+        assert!(result.rejected.is_empty());
+        assert_eq!(result.closed[&id(2)], CloseReason::NoLiquidity);
+        assert_eq!(result.unfilled[&id(2)], dec!(3));
+        // nothing was left behind on either side
+        assert!(engine.sell.is_empty());
+        assert_eq!(engine.iter_orders().count(), 1);
+        assert_eq!(engine.best_bid(), Some((dec!(99), dec!(1))));
+        assert_eq!(engine.check_consistency(), Ok(()));
+
+        // skipping the only maker, its own, is not a lack of liquidity
+        engine.set_self_trade_prevention(SelfTradePrevention::SkipOwnOrders);
+        let result = place(&mut engine, 3, market(3, Side::Sell, dec!(1)));
+        assert!(result.fills.is_empty());
+        assert_eq!(result.closed[&id(3)], CloseReason::IocCanceled);
+    }
+
+    #[test]
+    fn price_range_stops_a_taker_short_of_a_gapped_level() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(100.5)));
+        place(&mut engine, 1, limit(3, Side::Sell, dec!(1), dec!(105)));
+
+        // 105 is inside the limit but 5 away from the first fill at 100
+        let result = place(
+            &mut engine,
+            2,
+            limit(4, Side::Buy, dec!(5), dec!(110)).with_max_price_range(dec!(1)),
+        );
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(1), dec!(100)), (id(2), dec!(1), dec!(100.5))]
+        );
+        assert_eq!(result.closed[&id(4)], CloseReason::IocCanceled);
+        assert_eq!(result.unfilled[&id(4)], dec!(3));
+        assert_eq!(engine.best_ask(), Some((dec!(105), dec!(1))));
+        assert_eq!(engine.best_bid(), None);
+
+        // stopped by its own limit instead, the rest of a taker rests as usual
+        let result = place(
+            &mut engine,
+            3,
+            limit(5, Side::Buy, dec!(2), dec!(105)).with_max_price_range(dec!(1)),
+        );
+        assert_eq!(filled(&result), vec![(id(3), dec!(1), dec!(105))]);
+        assert_eq!(engine.best_bid(), Some((dec!(105), dec!(1))));
+    }
+
+    #[test]
+    fn desynced_expiry_index_is_caught_and_rebuilt() {
+        let mut engine = Engine::new();
+        place(
+            &mut engine,
+            1,
+            limit(1, Side::Sell, dec!(1), dec!(101)).with_tif(TimeInForce::GTD(10)),
+        );
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Buy, dec!(1), dec!(99)).with_tif(TimeInForce::GTD(20)),
+        );
+        place(&mut engine, 1, limit(3, Side::Buy, dec!(1), dec!(98)));
+        assert_eq!(engine.check_consistency(), Ok(()));
+
+        // drop one real entry and add one for an order that never expires
+        let first = *engine.expiry_uuid.iter().next().unwrap();
+        engine.expiry_uuid.remove(&first);
+        engine.expiry_uuid.insert(TimeUuid(5, 0, id(3)));
+        assert_eq!(
+            engine.check_consistency(),
+            Err("expiry index doesn't match the book".to_string())
+        );
+
+        engine.rebuild_expiry_index();
+        assert_eq!(engine.check_consistency(), Ok(()));
+        let expired: Vec<Uuid> = engine.flush(&15).iter().map(|order| order.uuid).collect();
+        assert_eq!(expired, vec![id(1)]);
+        assert!(engine.get_order(id(3)).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "inconsistent engine")]
+    fn debug_assert_consistent_panics_on_a_misindexed_order() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(101)));
+        engine.uuid_to_side_price_time.remove(&id(1));
+        engine.debug_assert_consistent();
+    }
+
+    #[test]
+    fn mid_between_ticks_is_rounded_onto_the_tick() {
+        let mid_peg = |n: u128, side: Side| Place::PeggedOrder {
+            uuid: id(n),
+            account: 0,
+            side,
+            amount: dec!(1),
+            reference: PegRef::Mid,
+            offset: dec!(0),
+            tif: TimeInForce::GTC,
+        };
+        // a spread of 0.5 puts the mid at 100.25
+        let book = |tick: Decimal, rounding: MidRounding| {
+            let mut engine = Engine::new();
+            engine.set_price_tick(tick);
+            engine.set_mid_rounding(rounding);
+            place(&mut engine, 1, limit(1, Side::Buy, dec!(1), dec!(100)));
+            place(&mut engine, 1, limit(2, Side::Sell, dec!(1), dec!(100.5)));
+            engine
+        };
+        let pegged_at = |tick, rounding, side| {
+            let mut engine = book(tick, rounding);
+            place(&mut engine, 2, mid_peg(3, side));
+            engine.get_order(id(3)).unwrap().price
+        };
+
+        assert_eq!(
+            pegged_at(dec!(0.1), MidRounding::Passive, Side::Buy),
+            dec!(100.2)
+        );
+        assert_eq!(
+            pegged_at(dec!(0.1), MidRounding::Passive, Side::Sell),
+            dec!(100.3)
+        );
+        assert_eq!(
+            pegged_at(dec!(0.1), MidRounding::Aggressive, Side::Buy),
+            dec!(100.3)
+        );
+        assert_eq!(
+            pegged_at(dec!(0.1), MidRounding::Aggressive, Side::Sell),
+            dec!(100.2)
+        );
+        // with no tick the mid is used as it is
+        assert_eq!(
+            pegged_at(dec!(0), MidRounding::Passive, Side::Buy),
+            dec!(100.25)
+        );
+
+        /*
+            A mid market buy is limited to the rounded mid the same way: when
+            passive it bids 100.2 to a sell pegged at 100.3, and when
+            aggressive 100.3 to one at 100.2
+        */
+        for (rounding, fills) in [
+            (MidRounding::Passive, vec![]),
+            (MidRounding::Aggressive, vec![(id(3), dec!(1), dec!(100.2))]),
+        ] {
+            let mut engine = book(dec!(0.1), rounding);
+            place(&mut engine, 2, mid_peg(3, Side::Sell));
+            let result = place(&mut engine, 3, mid_market(4, Side::Buy, dec!(1)));
+            assert_eq!(filled(&result), fills);
+        }
+    }
+
+    #[test]
+    fn price_improvement_is_only_reported_against_a_real_limit() {
+        let mut engine = Engine::new();
+        engine.set_report_price_improvement(true);
+        engine.set_reference_band(Some(dec!(0.1)));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Buy, dec!(1), dec!(100)));
+        for n in 3..=6 {
+            place(&mut engine, 2, limit(n, Side::Sell, dec!(1), dec!(100)));
+        }
+
+        // the limits slippage and the band give a market order aren't its own
+        let result = place(
+            &mut engine,
+            3,
+            market(7, Side::Buy, dec!(1)).with_max_slippage(dec!(5)),
+        );
+        assert_eq!(result.fills[0].price_improvement, None);
+        let result = place(&mut engine, 3, market(8, Side::Buy, dec!(1)));
+        assert_eq!(result.fills[0].price_improvement, None);
+        let result = place(&mut engine, 4, limit(9, Side::Buy, dec!(1), dec!(102)));
+        assert_eq!(result.fills[0].price_improvement, Some(dec!(2)));
+    }
+
+    #[test]
+    fn cancel_on_disconnect_orders_go_when_heartbeats_stop() {
+        let mut engine = Engine::new();
+        engine.set_heartbeat_timeout(Some(10));
+        let flagged = |n, account, price| {
+            limit(n, Side::Buy, dec!(1), price)
+                .with_account(account)
+                .with_cancel_on_disconnect()
+        };
+        // placing a flagged order counts as a heartbeat
+        place(&mut engine, 1, flagged(1, 1, dec!(99)));
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Buy, dec!(1), dec!(98)).with_account(1),
+        );
+        place(&mut engine, 1, flagged(3, 2, dec!(97)));
+
+        // account 1 keeps up its heartbeats for a while, account 2 never does
+        call(&mut engine, 8, Command::Heartbeat(1));
+        let result = call(&mut engine, 11, Command::Heartbeat(1));
+        assert_eq!(
+            result.closed,
+            BTreeMap::from([(id(3), CloseReason::Disconnected)])
+        );
+        let result = call(&mut engine, 20, Command::Heartbeat(2));
+        assert!(result.closed.is_empty());
+        assert!(engine.get_order(id(1)).is_some());
+
+        // 10 after its last heartbeat, only the flagged order goes
+        let result = call(&mut engine, 21, Command::Heartbeat(2));
+        assert_eq!(
+            result.closed,
+            BTreeMap::from([(id(1), CloseReason::Disconnected)])
+        );
+        assert!(engine.get_order(id(2)).is_some());
+        assert_eq!(engine.iter_orders().count(), 1);
+        assert_eq!(engine.check_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn call_batch_matches_calling_one_at_a_time() {
+        let mut engine = Engine::new();
+        engine.set_heartbeat_timeout(Some(5));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(101)));
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Buy, dec!(1), dec!(99)).with_tif(TimeInForce::GTD(3)),
+        );
+        let mut batched = Engine::restore(engine.snapshot());
+
+        let commands = || {
+            vec![
+                // three in one tick, the last expiring in the tick it is placed in
+                (2, Command::Place(limit(3, Side::Buy, dec!(1), dec!(98)))),
+                (
+                    2,
+                    Command::Place(
+                        limit(4, Side::Sell, dec!(1), dec!(102)).with_tif(TimeInForce::GTT(3)),
+                    ),
+                ),
+                (
+                    2,
+                    Command::Place(
+                        limit(5, Side::Sell, dec!(1), dec!(103)).with_tif(TimeInForce::GTD(0)),
+                    ),
+                ),
+                // so it is gone by the next command, still in that tick
+                (2, Command::Cancel(id(9))),
+                // a new tick, where orders 4 and 2 expire
+                (4, Command::Place(market(6, Side::Sell, dec!(1)))),
+                (
+                    4,
+                    Command::Place(
+                        limit(7, Side::Buy, dec!(1), dec!(97))
+                            .with_account(1)
+                            .with_cancel_on_disconnect(),
+                    ),
+                ),
+                (6, Command::Place(limit(8, Side::Buy, dec!(2), dec!(101)))),
+                // back in time is refused either way
+                (5, Command::Cancel(id(3))),
+                // account 1 has gone quiet by now
+                (9, Command::Cancel(id(1))),
+                (9, Command::Cancel(id(3))),
+            ]
+            .into_iter()
+            .map(|(now, command)| CommandAtTime::new(now, command))
+            .collect::<Vec<_>>()
+        };
+
+        let one_at_a_time: Vec<String> = commands()
+            .into_iter()
+            .map(|command| format!("{:?}", engine.call(command)))
+            .collect();
+        let in_a_batch: Vec<String> = batched
+            .call_batch(commands())
+            .iter()
+            .map(|result| format!("{:?}", result))
+            .collect();
+        assert_eq!(in_a_batch, one_at_a_time);
+        assert!(one_at_a_time[3].contains("Expired"));
+        assert_eq!(one_at_a_time[4].matches("ExpiredOrder").count(), 2);
+        assert!(one_at_a_time[7].starts_with("Err(OutOfOrder"));
+        assert!(one_at_a_time[8].contains("Disconnected"));
+        assert_eq!(
+            serde_json::to_string(&batched.snapshot()).unwrap(),
+            serde_json::to_string(&engine.snapshot()).unwrap()
+        );
+    }
+
+    #[test]
+    fn triggered_stops_cascade_and_a_stop_limit_rests() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Buy, dec!(1), dec!(100)));
+        for (n, price) in [(3, dec!(99)), (4, dec!(98)), (5, dec!(97))] {
+            place(&mut engine, 1, limit(n, Side::Buy, dec!(1), price));
+        }
+        place(&mut engine, 2, stop(6, Side::Sell, dec!(99)));
+        place(&mut engine, 2, stop(7, Side::Sell, dec!(98)));
+        place(
+            &mut engine,
+            2,
+            stop_limit(8, Side::Sell, dec!(97), dec!(96.5)),
+        );
+        assert_eq!(engine.sell_stops.len(), 3);
+
+        // a trade at 99 sets off the first stop, whose fill at 98 sets off the next
+        let result = place(&mut engine, 3, limit(9, Side::Sell, dec!(1), dec!(99)));
+        let takers: Vec<(Uuid, Decimal)> = result
+            .fills
+            .iter()
+            .map(|fill| (fill.taker_uuid, fill.price))
+            .collect();
+        assert_eq!(
+            takers,
+            vec![(id(9), dec!(99)), (id(6), dec!(98)), (id(7), dec!(97))]
+        );
+        // the stop-limit triggered at 97 too, but rests with nothing bid at 96.5
+        assert!(engine.sell_stops.is_empty());
+        assert_eq!(engine.best_ask(), Some((dec!(96.5), dec!(1))));
+        assert_eq!(engine.best_bid(), None);
+
+        // a stop already past its price is placed straight away
+        let result = place(&mut engine, 4, stop(10, Side::Buy, dec!(95)));
+        assert_eq!(filled(&result), vec![(id(8), dec!(1), dec!(96.5))]);
+        assert!(engine.buy_stops.is_empty());
+        assert_eq!(engine.check_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn iceberg_refills_its_slice_at_the_back_of_the_queue() {
+        let mut engine = Engine::new();
+        place(
+            &mut engine,
+            1,
+            iceberg(1, Side::Sell, dec!(5), dec!(100), dec!(2)),
+        );
+        place(&mut engine, 2, limit(2, Side::Sell, dec!(1), dec!(100)));
+        let shown = |engine: &Engine| {
+            let order = engine.get_order(id(1)).unwrap();
+            (order.remaining_amount, order.hidden_amount)
+        };
+        assert_eq!(shown(&engine), (dec!(2), dec!(3)));
+        assert_eq!(engine.best_ask(), Some((dec!(100), dec!(3))));
+
+        // taking the slice shows the next, behind the order that was waiting
+        let result = place(&mut engine, 3, limit(3, Side::Buy, dec!(2), dec!(100)));
+        assert_eq!(filled(&result), vec![(id(1), dec!(2), dec!(100))]);
+        assert_eq!(shown(&engine), (dec!(2), dec!(1)));
+        let result = place(&mut engine, 4, limit(4, Side::Buy, dec!(2), dec!(100)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(2), dec!(1), dec!(100)), (id(1), dec!(1), dec!(100))]
+        );
+
+        // the last slice is only what was left hidden
+        let result = place(&mut engine, 5, limit(5, Side::Buy, dec!(5), dec!(100)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(1), dec!(100)), (id(1), dec!(1), dec!(100))]
+        );
+        assert!(engine.get_order(id(1)).is_none());
+        assert_eq!(engine.best_bid(), Some((dec!(100), dec!(3))));
+    }
+
+    #[test]
+    fn amend_keeps_priority_only_on_a_size_decrease() {
+        // the maker a buy of 1 at 100 fills after order 1 is amended
+        let first_after = |new_price, new_amount| {
+            let mut engine = Engine::new();
+            place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(100)));
+            place(&mut engine, 2, limit(2, Side::Sell, dec!(2), dec!(100)));
+            let amend = Command::Amend {
+                uuid: id(1),
+                new_price,
+                new_amount,
+            };
+            let result = call(&mut engine, 3, amend);
+            assert!(result.fills.is_empty() && result.rejected.is_empty());
+            let result = place(&mut engine, 4, limit(4, Side::Buy, dec!(1), dec!(100)));
+            filled(&result)[0].0
+        };
+
+        assert_eq!(first_after(dec!(100), dec!(1)), id(1));
+        assert_eq!(first_after(dec!(100), dec!(3)), id(2));
+        // a price change loses it even when moved away and back again
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(2), dec!(100)));
+        place(&mut engine, 2, limit(2, Side::Sell, dec!(2), dec!(100)));
+        let amend = |new_price| Command::Amend {
+            uuid: id(1),
+            new_price,
+            new_amount: dec!(2),
+        };
+        call(&mut engine, 3, amend(dec!(101)));
+        assert_eq!(engine.best_ask(), Some((dec!(100), dec!(2))));
+        call(&mut engine, 4, amend(dec!(100)));
+        let result = place(&mut engine, 5, limit(3, Side::Buy, dec!(1), dec!(100)));
+        assert_eq!(filled(&result), vec![(id(2), dec!(1), dec!(100))]);
+        assert_eq!(engine.get_order(id(1)).unwrap().created, 4);
+    }
+
+    #[test]
+    fn fok_that_cannot_fill_in_full_leaves_the_book_untouched() {
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 1, limit(2, Side::Sell, dec!(2), dec!(101)));
+        place(&mut engine, 1, limit(3, Side::Sell, dec!(5), dec!(102)));
+        let before: Vec<OrderView> = engine.iter_orders().collect();
+
+        // 3 is there within its limit, but not 4
+        let fok = |n, amount| limit(n, Side::Buy, amount, dec!(101)).with_tif(TimeInForce::FOK);
+        let result = place(&mut engine, 2, fok(4, dec!(4)));
+        assert!(result.fills.is_empty());
+        assert_eq!(result.closed[&id(4)], CloseReason::IocCanceled);
+        assert_eq!(result.unfilled[&id(4)], dec!(4));
+        assert_eq!(engine.iter_orders().collect::<Vec<_>>(), before);
+        assert_eq!(engine.stats().fills, 0);
+
+        let result = place(&mut engine, 3, fok(5, dec!(3)));
+        assert_eq!(
+            filled(&result),
+            vec![(id(1), dec!(1), dec!(100)), (id(2), dec!(2), dec!(101))]
+        );
+        assert_eq!(result.closed[&id(5)], CloseReason::Filled);
+    }
+
+    #[test]
+    fn self_trade_prevention_modes() {
+        for policy in [MatchingPolicy::PriceTime, MatchingPolicy::ProRata] {
+            let book = |stp| {
+                let mut engine = Engine::new();
+                engine.set_matching_policy(policy);
+                engine.set_self_trade_prevention(stp);
+                place(
+                    &mut engine,
+                    1,
+                    limit(1, Side::Sell, dec!(1), dec!(100)).with_account(7),
+                );
+                place(
+                    &mut engine,
+                    1,
+                    limit(2, Side::Sell, dec!(1), dec!(100)).with_account(8),
+                );
+                place(
+                    &mut engine,
+                    1,
+                    limit(3, Side::Sell, dec!(1), dec!(101)).with_account(7),
+                );
+                engine
+            };
+
+            // Allow lets an account trade with itself
+            let mut engine = book(SelfTradePrevention::Allow);
+            let buy = limit(4, Side::Buy, dec!(3), dec!(101)).with_account(7);
+            let result = place(&mut engine, 2, buy.clone());
+            assert_eq!(result.fills.len(), 3, "{:?}", policy);
+
+            // SkipOwnOrders passes over its own makers, which stay on the book
+            let mut engine = book(SelfTradePrevention::SkipOwnOrders);
+            let result = place(&mut engine, 2, buy);
+            assert_eq!(
+                filled(&result),
+                vec![(id(2), dec!(1), dec!(100))],
+                "{:?}",
+                policy
+            );
+            assert!(engine.get_order(id(1)).is_some());
+            assert!(engine.get_order(id(3)).is_some());
+            // what is left rests, even though it crosses its own asks
+            assert_eq!(engine.best_bid(), Some((dec!(101), dec!(2))));
+
+            // and a market order is cancelled rather than trade with itself
+            let result = place(
+                &mut engine,
+                3,
+                market(5, Side::Buy, dec!(1)).with_account(7),
+            );
+            assert!(result.fills.is_empty());
+            assert_eq!(result.closed[&id(5)], CloseReason::IocCanceled);
+        }
+    }
+
+    #[test]
+    fn simulate_leaves_the_engine_unchanged() {
+        let mut engine = Engine::new();
+        engine.set_report_book_deltas(true);
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(
+            &mut engine,
+            1,
+            limit(2, Side::Sell, dec!(2), dec!(101)).with_tif(TimeInForce::GTD(5)),
+        );
+        place(&mut engine, 1, stop(3, Side::Buy, dec!(101)));
+        let before = serde_json::to_string(&engine.snapshot()).unwrap();
+
+        // fills, sets off the stop and expires order 2 on the copy
+        let simulated = engine.simulate(&limit(4, Side::Buy, dec!(2), dec!(101)), 3);
+        assert_eq!(simulated.fills.len(), 3);
+        let late = engine.simulate(&limit(5, Side::Buy, dec!(2), dec!(101)), 6);
+        assert_eq!(late.expired.len(), 1);
+        assert_eq!(serde_json::to_string(&engine.snapshot()).unwrap(), before);
+
+        // and the real thing does what it said it would
+        let result = place(&mut engine, 3, limit(4, Side::Buy, dec!(2), dec!(101)));
+        assert_eq!(filled(&result), filled(&simulated));
+        assert_eq!(result.closed, simulated.closed);
+    }
+
+    #[test]
+    fn replace_cancels_then_places_at_the_back_of_the_queue() {
+        let replace = |cancel_uuid, place| Command::Replace { cancel_uuid, place };
+        let mut engine = Engine::new();
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(&mut engine, 2, limit(2, Side::Sell, dec!(1), dec!(100)));
+
+        // the same price and size, but a new order behind the one that waited
+        let result = call(
+            &mut engine,
+            3,
+            replace(id(1), limit(3, Side::Sell, dec!(1), dec!(100))),
+        );
+        assert_eq!(result.closed[&id(1)], CloseReason::CanceledByUser);
+        assert!(engine.get_order(id(1)).is_none());
+        let result = place(&mut engine, 4, limit(4, Side::Buy, dec!(1), dec!(100)));
+        assert_eq!(filled(&result), vec![(id(2), dec!(1), dec!(100))]);
+
+        // reusing the uuid is refused and leaves the order alone
+        let result = call(
+            &mut engine,
+            5,
+            replace(id(3), limit(3, Side::Sell, dec!(2), dec!(100))),
+        );
+        assert_eq!(result.rejected[&id(3)], RejectReason::DuplicateUuid);
+        assert_eq!(engine.get_order(id(3)).unwrap().amount, dec!(1));
+
+        // with the old order gone, the new one is rejected, or placed if configured so
+        let result = call(
+            &mut engine,
+            6,
+            replace(id(1), limit(5, Side::Sell, dec!(1), dec!(101))),
+        );
+        assert_eq!(result.rejected[&id(5)], RejectReason::UnknownOrder);
+        assert!(engine.get_order(id(5)).is_none());
+        engine.set_replace_missing(ReplaceMissing::Place);
+        let result = call(
+            &mut engine,
+            7,
+            replace(id(1), limit(5, Side::Sell, dec!(1), dec!(101))),
+        );
+        assert!(result.rejected.is_empty() && result.closed.is_empty());
+        assert!(engine.get_order(id(5)).is_some());
+    }
+
+    #[test]
+    fn on_fill_sees_each_fill_once_in_fill_order() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let hook_seen = seen.clone();
+        engine.set_on_fill(Some(Box::new(move |fill: &Fill| {
+            hook_seen.lock().unwrap().push((
+                fill.maker_uuid,
+                fill.taker_uuid,
+                fill.base_amount,
+                fill.price,
+            ));
+        })));
+        place(&mut engine, 1, limit(1, Side::Sell, dec!(1), dec!(100)));
+        place(
+            &mut engine,
+            1,
+            iceberg(2, Side::Sell, dec!(3), dec!(101), dec!(1)),
+        );
+        place(&mut engine, 1, stop(3, Side::Buy, dec!(101)));
+        assert!(seen.lock().unwrap().is_empty());
+
+        // a sweep, including iceberg refills and the stop it sets off
+        let result = place(&mut engine, 2, limit(4, Side::Buy, dec!(3), dec!(101)));
+        let fills: Vec<(Uuid, Uuid, Decimal, Decimal)> = result
+            .fills
+            .iter()
+            .map(|fill| {
+                (
+                    fill.maker_uuid,
+                    fill.taker_uuid,
+                    fill.base_amount,
+                    fill.price,
+                )
+            })
+            .collect();
+        assert_eq!(fills.len(), 4);
+        assert_eq!(fills[3].1, id(3));
+        assert_eq!(*seen.lock().unwrap(), fills);
+
+        engine.set_on_fill(None);
+        place(&mut engine, 3, limit(5, Side::Sell, dec!(1), dec!(101)));
+        let result = place(&mut engine, 3, limit(6, Side::Buy, dec!(1), dec!(101)));
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+}
@@ -1,501 +1,531 @@
+use std::env;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::BufRead;
-use std::str::FromStr;
-
-use rust_decimal::prelude::*;
-use std::cmp;
-use std::collections::BTreeMap;
-use std::collections::BTreeSet;
-use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
 use uuid::Uuid;
 
-// time is in nanoseconds
-const SECOND: u64 = 1000 * 1000 * 1000;
-const DAY: u64 = SECOND * 60 * 60 * 24;
-const MAX_LIFETIME: u64 = 90 * DAY;
+use matchbox::{
+    parse_json_line_in, parse_line_in, replay, CloseReason, CommandLog, Engine, LevelChange,
+    MatchResult, Side, TimeUnit,
+};
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
-enum Side {
-    Buy,
-    Sell,
-}
+#[cfg(feature = "server")]
+mod server;
 
-fn other_side(side: Side) -> Side {
-    match side {
-        Side::Buy => Side::Sell,
-        Side::Sell => Side::Buy,
-    }
+enum Format {
+    Legacy,
+    Json,
+    Fix,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct SideParseError(());
+enum Input {
+    Csv,
+    Json,
+}
 
-impl FromStr for Side {
-    type Err = SideParseError;
+struct Options {
+    format: Format,
+    input: Input,
+    time_unit: TimeUnit, // of the input; everything internal and output is nanoseconds
+    log: Option<String>,
+    recover: bool, // replay `log` before reading any input
+    book_deltas: bool,
+    jitter_seed: Option<u64>, // iceberg slices are only jittered with a seed
+    heartbeat_timeout: Option<u64>, // in --time-unit
+    reject_unknown_cancels: bool,
+    symbol: String,                 // the instrument named in --format fix output
+    replay_speed: Option<f64>,      // pace stdin to its timestamps, this many times faster
+    paths: Vec<String>,             // files of commands read in turn, or stdin if none
+    log_level: Option<LevelFilter>, // engine events to stderr, at this level and above
+    #[cfg(feature = "server")]
+    listen: Option<String>, // serve on this address instead of reading stdin
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "buy" => Ok(Side::Buy),
-            "sell" => Ok(Side::Sell),
-            _ => Err(SideParseError(())),
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        format: Format::Legacy,
+        input: Input::Csv,
+        time_unit: TimeUnit::Nanoseconds,
+        log: None,
+        recover: false,
+        book_deltas: false,
+        jitter_seed: None,
+        heartbeat_timeout: None,
+        reject_unknown_cancels: false,
+        symbol: "MATCHBOX".to_string(),
+        replay_speed: None,
+        paths: Vec::new(),
+        log_level: None,
+        #[cfg(feature = "server")]
+        listen: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                options.format = match args.next().map(|s| s.as_str()) {
+                    Some("legacy") => Format::Legacy,
+                    Some("json") => Format::Json,
+                    Some("fix") => Format::Fix,
+                    _ => return Err("--format must be one of: legacy, json, fix".to_string()),
+                }
+            }
+            "--input" => {
+                options.input = match args.next().map(|s| s.as_str()) {
+                    Some("csv") => Input::Csv,
+                    Some("json") => Input::Json,
+                    _ => return Err("--input must be one of: csv, json".to_string()),
+                }
+            }
+            "--time-unit" => {
+                options.time_unit = match args.next().map(|s| s.as_str()) {
+                    Some("ns") => TimeUnit::Nanoseconds,
+                    Some("us") => TimeUnit::Microseconds,
+                    Some("ms") => TimeUnit::Milliseconds,
+                    _ => return Err("--time-unit must be one of: ns, us, ms".to_string()),
+                }
+            }
+            "--log" => match args.next() {
+                Some(path) => options.log = Some(path.clone()),
+                None => return Err("--log needs a path".to_string()),
+            },
+            "--recover" => options.recover = true,
+            "--book-deltas" => options.book_deltas = true,
+            "--jitter-seed" => match args.next().map(|s| s.parse()) {
+                Some(Ok(seed)) => options.jitter_seed = Some(seed),
+                _ => return Err("--jitter-seed needs a number".to_string()),
+            },
+            "--heartbeat-timeout" => match args.next().map(|s| s.parse()) {
+                Some(Ok(timeout)) => options.heartbeat_timeout = Some(timeout),
+                _ => return Err("--heartbeat-timeout needs a number".to_string()),
+            },
+            "--reject-unknown-cancels" => options.reject_unknown_cancels = true,
+            "--symbol" => match args.next() {
+                Some(symbol) => options.symbol = symbol.clone(),
+                None => return Err("--symbol needs a name".to_string()),
+            },
+            "--replay-speed" => match args.next().map(|s| s.parse::<f64>()) {
+                Some(Ok(speed)) if speed.is_finite() && speed > 0.0 => {
+                    options.replay_speed = Some(speed)
+                }
+                _ => return Err("--replay-speed needs a positive number".to_string()),
+            },
+            "--log-level" => match args.next().map(|s| s.parse()) {
+                Some(Ok(level)) => options.log_level = Some(level),
+                _ => {
+                    return Err(
+                        "--log-level must be one of: off, error, warn, info, debug, trace"
+                            .to_string(),
+                    )
+                }
+            },
+            #[cfg(feature = "server")]
+            "--listen" => match args.next() {
+                Some(address) => options.listen = Some(address.clone()),
+                None => return Err("--listen needs an address".to_string()),
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown argument: {}", arg)),
+            _ => options.paths.push(arg.clone()),
         }
     }
+    if options.recover && options.log.is_none() {
+        return Err("--recover needs --log".to_string());
+    }
+    Ok(options)
 }
 
-#[derive(Copy, Clone, Debug)]
-enum TimeInForce {
-    GTC,
-    IOC,
-    GTD(u64), // lifetime in nanoseconds
-}
 /*
-
-    Not sure yet how to specify (im)mutability in
-    nested structs in rust.
-
-    I'd like to make Order mostly immutable, apart from `remaining_amount`,
-    but it has to live inside a BTreeMap which is obviously mutable.
+    Canonical output order for one command:
+      1. orders expired by the flush before it, in expiry order
+      2. fills, in the order they were matched
+      3. closed uuids, in the order they were closed
+      4. rejected uuids, in the order they were rejected
+      5. with --book-deltas, the price levels that changed, buys then sells
+    Nothing is ordered by uuid, so given the same commands the output is
+    the same byte for byte, however the uuids were generated.
+    Expired, fill and closed lines end with the event sequence number,
+    which goes up by one per line; a jump means a line was lost. A fill
+    line has the taker's price improvement after that, if it is reported,
+    and a closed line what was left unfilled of an IOC, FOK or market
+    order. Times are in nanoseconds, whatever --time-unit the input used.
 */
-struct Order {
-    uuid: Uuid,
-    side: Side,
-    created: u64,
-    amount: Decimal,
-    price: Decimal,
-    tif: TimeInForce,
-    //This is the only field that needs to be mutable; maybe
-    // we should use Cell<Decimal> ??
-    remaining_amount: Decimal,
-}
-#[derive(Debug)]
-struct Fill {
-    base_amount: Decimal,
-    price: Decimal,
-    maker_uuid: Uuid,
-    taker_uuid: Uuid,
-}
-
-impl Fill {
-    fn quote_amount(&self) -> Decimal {
-        return self.base_amount * self.price;
+fn write_result(out: &mut impl Write, result: &MatchResult, now: u64) -> io::Result<()> {
+    for expired in &result.expired {
+        writeln!(
+            out,
+            "< {},expired,{},{},{},{},{}",
+            now,
+            expired.uuid,
+            expired.side,
+            expired.price,
+            expired.remaining_amount,
+            expired.sequence
+        )?;
     }
+    for fill in &result.fills {
+        write!(
+            out,
+            "< {},fill,{},{},{},{},{},{},{},{}",
+            now,
+            fill.maker_uuid,
+            fill.taker_uuid,
+            fill.base_amount,
+            fill.price,
+            fill.maker_fee,
+            fill.taker_fee,
+            fill.taker_side,
+            fill.sequence
+        )?;
+        match fill.price_improvement {
+            Some(improvement) => writeln!(out, ",{}", improvement)?,
+            None => writeln!(out)?,
+        }
+    }
+    let mut closed: Vec<(&Uuid, &u64)> = result.closed_sequence.iter().collect();
+    closed.sort_by_key(|(_, sequence)| **sequence);
+    for (uuid, sequence) in closed {
+        match result.unfilled.get(uuid) {
+            Some(unfilled) => writeln!(out, "< {},closed,{},{},{}", now, uuid, sequence, unfilled)?,
+            None => writeln!(out, "< {},closed,{},{}", now, uuid, sequence)?,
+        }
+    }
+    for (uuid, reason) in result.rejected_in_order() {
+        writeln!(out, "< {},rejected,{},{}", now, uuid, reason)?;
+    }
+    for delta in &result.book_deltas {
+        writeln!(
+            out,
+            "< {},level,{},{},{},{}",
+            now, delta.side, delta.price, delta.size, delta.change
+        )?;
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct MatchResult {
-    fills: Vec<Fill>,
-    closed: BTreeSet<Uuid>,
-}
-
-#[derive(Debug)]
-enum Place {
-    MarketOrder {
-        uuid: Uuid,
-        side: Side,
-        amount: Decimal,
-    },
-    LimitOrder {
-        uuid: Uuid,
-        side: Side,
-        amount: Decimal,
-        tif: TimeInForce,
-        price: Decimal,
-    },
+#[derive(Serialize)]
+struct JsonResult<'a> {
+    now: u64,
+    #[serde(flatten)]
+    result: &'a MatchResult,
 }
 
-#[derive(Debug)]
-enum Command {
-    Place(Place),
-    Cancel(Uuid),
-    Flush(),
+fn write_json_result(out: &mut impl Write, result: &MatchResult, now: u64) -> io::Result<()> {
+    let json = serde_json::to_string(&JsonResult { now, result }).unwrap();
+    writeln!(out, "{}", json)
 }
 
-#[derive(Debug)]
-struct CommandAtTime {
+/*
+    --format fix writes the same events in the same order as the legacy
+    format, one tag=value|tag=value line each. Everything is an execution
+    report (35=8) except level changes. Tags used:
+      35  MsgType: 8 execution report, X market data incremental refresh
+      150 ExecType: F trade, C expired, 4 cancelled, I closed (filled), 8 rejected
+      39  OrdStatus: 2 filled, 4 cancelled, C expired, 8 rejected
+      17  ExecID: the event sequence number
+      60  TransactTime: now, in nanoseconds
+      55  Symbol: --symbol
+      37  OrderID: the order's uuid; for a fill, the taker's
+      54  Side: 1 buy, 2 sell; for a fill, the taker's
+      32  LastQty and 31 LastPx: the fill's amount and price
+      12  Commission: the taker's fee
+      44  Price: an expired order's price
+      84  CxlQty: what was left of an order that expired or was cancelled
+      58  Text: why an order was closed or rejected
+      5001 the maker's uuid and 5002 the maker's fee, on a fill
+      5003 the taker's price improvement, on a fill, if it is reported
+    A level change (35=X) has 279 MDUpdateAction (0 new, 1 change,
+    2 delete), 269 MDEntryType (0 bid, 1 offer), 270 MDEntryPx and
+    271 MDEntrySize.
+*/
+fn write_fix_result(
+    out: &mut impl Write,
+    result: &MatchResult,
     now: u64,
-    command: Command,
-}
-
-impl Order {
-    fn create(place: Place, now: u64) -> Order {
-        match place {
-            Place::MarketOrder { uuid, side, amount } => Order {
-                uuid: uuid,
-                created: now,
-                side: side,
-                amount: amount,
-                tif: TimeInForce::IOC,
-                price: match side {
-                    Side::Buy => Decimal::MAX,
-                    Side::Sell => Decimal::ZERO,
-                },
-                remaining_amount: amount,
-            },
-            Place::LimitOrder {
-                uuid,
-                side,
-                amount,
-                tif,
-                price,
-            } => Order {
-                uuid: uuid,
-                created: now,
-                side: side,
-                amount: amount,
-                tif: tif,
-                price: price,
-                remaining_amount: amount,
-            },
+    symbol: &str,
+) -> io::Result<()> {
+    let side = |side: Side| match side {
+        Side::Buy => 1,
+        Side::Sell => 2,
+    };
+    for expired in &result.expired {
+        writeln!(
+            out,
+            "35=8|150=C|39=C|17={}|60={}|55={}|37={}|54={}|44={}|84={}",
+            expired.sequence,
+            now,
+            symbol,
+            expired.uuid,
+            side(expired.side),
+            expired.price,
+            expired.remaining_amount
+        )?;
+    }
+    for fill in &result.fills {
+        write!(
+            out,
+            "35=8|150=F|17={}|60={}|55={}|37={}|54={}|32={}|31={}|12={}|5001={}|5002={}",
+            fill.sequence,
+            now,
+            symbol,
+            fill.taker_uuid,
+            side(fill.taker_side),
+            fill.base_amount,
+            fill.price,
+            fill.taker_fee,
+            fill.maker_uuid,
+            fill.maker_fee
+        )?;
+        match fill.price_improvement {
+            Some(improvement) => writeln!(out, "|5003={}", improvement)?,
+            None => writeln!(out)?,
         }
     }
-
-    fn expiry(&self) -> u64 {
-        match self.tif {
-            TimeInForce::IOC => self.created,
-            TimeInForce::GTC => self.created + MAX_LIFETIME,
-            TimeInForce::GTD(lifetime) => self.created + lifetime,
+    let mut closed: Vec<(&Uuid, &u64)> = result.closed_sequence.iter().collect();
+    closed.sort_by_key(|(_, sequence)| **sequence);
+    for (uuid, sequence) in closed {
+        let (exec_type, status, text) = match result.closed[uuid] {
+            CloseReason::Filled => ("I", "2", "filled"),
+            CloseReason::CanceledByUser => ("4", "4", "canceled_by_user"),
+            CloseReason::IocCanceled => ("4", "4", "ioc_canceled"),
+            CloseReason::Expired => ("C", "C", "expired"),
+            CloseReason::Rejected => ("8", "8", "rejected"),
+            CloseReason::ReduceOnly => ("4", "4", "reduce_only"),
+            CloseReason::Disconnected => ("4", "4", "disconnected"),
+            CloseReason::NoLiquidity => ("4", "4", "no_liquidity"),
+        };
+        write!(
+            out,
+            "35=8|150={}|39={}|17={}|60={}|55={}|37={}|58={}",
+            exec_type, status, sequence, now, symbol, uuid, text
+        )?;
+        match result.unfilled.get(uuid) {
+            Some(unfilled) => writeln!(out, "|84={}", unfilled)?,
+            None => writeln!(out)?,
         }
     }
+    for (uuid, reason) in result.rejected_in_order() {
+        writeln!(
+            out,
+            "35=8|150=8|39=8|60={}|55={}|37={}|58={}",
+            now, symbol, uuid, reason
+        )?;
+    }
+    for delta in &result.book_deltas {
+        let action = match delta.change {
+            LevelChange::Added => 0,
+            LevelChange::Changed => 1,
+            LevelChange::Removed => 2,
+        };
+        let entry = match delta.side {
+            Side::Buy => 0,
+            Side::Sell => 1,
+        };
+        writeln!(
+            out,
+            "35=X|60={}|55={}|279={}|269={}|270={}|271={}",
+            now, symbol, action, entry, delta.price, delta.size
+        )?;
+    }
+    Ok(())
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-struct PriceTime(Decimal, u64);
-
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-struct SidePriceTime(Side, Decimal, u64);
-
-
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-struct TimeUuid(u64,Uuid);
-
+/*
+    Sleeps between commands for as long as their timestamps say passed
+    between them, divided by the speed. Time going backwards doesn't sleep,
+    and no single pause is longer than MAX_PAUSE, so a gap in a recording
+    doesn't stall the replay.
+*/
+struct Pacer {
+    speed: f64,
+    last: Option<u64>,
+}
 
+const MAX_PAUSE: Duration = Duration::from_secs(10);
 
+impl Pacer {
+    fn new(speed: f64) -> Pacer {
+        Pacer { speed, last: None }
+    }
 
-struct Engine {
-    buy: BTreeMap<PriceTime, Order>,
-    sell: BTreeMap<PriceTime, Order>,
-    last_tick: u64,
-    uuid_to_side_price_time: HashMap<Uuid, SidePriceTime>,
-    expiry_uuid:BTreeSet<TimeUuid>
+    fn wait(&mut self, now: u64) {
+        if let Some(last) = self.last {
+            let delta = now.saturating_sub(last) as f64 / self.speed;
+            let pause = Duration::try_from_secs_f64(delta / 1e9).unwrap_or(MAX_PAUSE);
+            thread::sleep(pause.min(MAX_PAUSE));
+        }
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+    }
 }
 
-fn crossed(taker: &Order, maker: &Order) -> bool {
-    if taker.remaining_amount.is_zero() {
-        return false;
+/*
+    Apply one input line and write out what happened. A line that can't be
+    parsed or applied is reported on stderr and otherwise ignored.
+*/
+fn process_line(
+    engine: &mut Engine,
+    line: &str,
+    options: &Options,
+    pacer: Option<&mut Pacer>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    // stray blank lines and trailing whitespace in piped input are ignored
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+    if let Format::Legacy = options.format {
+        writeln!(out, "> {}", line)?;
+    }
+    let parsed = match options.input {
+        Input::Csv => parse_line_in(line.to_string(), options.time_unit),
+        Input::Json => parse_json_line_in(line.to_string(), options.time_unit),
+    };
+    let command_at_time = match parsed {
+        Ok(command_at_time) => command_at_time,
+        Err(e) => {
+            eprintln!("skipping line: {}", e);
+            return Ok(());
+        }
     };
-    match taker.side {
-        Side::Buy => taker.price >= maker.price,
-        Side::Sell => taker.price <= maker.price,
+    let now = command_at_time.now;
+    if let Some(pacer) = pacer {
+        out.flush()?;
+        pacer.wait(now);
+    }
+    let result = match engine.call(command_at_time) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("skipping line: {}", e);
+            return Ok(());
+        }
+    };
+    match options.format {
+        Format::Legacy => write_result(out, &result, now),
+        Format::Json => write_json_result(out, &result, now),
+        Format::Fix => write_fix_result(out, &result, now, &options.symbol),
     }
 }
 
-fn merge(r1: MatchResult, closed: BTreeSet<Uuid>) -> MatchResult {
-    MatchResult {
-        fills: r1.fills,
-        closed: r1.closed.union(&closed).map(|u| u.clone()).collect(),
+fn open_log(mut engine: Engine, path: &str, recover: bool) -> io::Result<Engine> {
+    if recover {
+        engine = replay(engine, BufReader::new(File::open(path)?))?;
     }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    engine.set_command_log(CommandLog::new(file));
+    Ok(engine)
 }
 
-impl Engine {
-    fn _match(&mut self, taker: &mut Order) -> MatchResult {
-        let mut result = MatchResult {
-            closed: BTreeSet::new(),
-            fills: Vec::new(),
-        };
+// engine events go to stderr, so they never mix with results on stdout
+struct StderrLogger;
 
-        let resting = &mut self.resting(other_side(taker.side));
-        for (_, maker) in resting.iter_mut() {
-            if !crossed(&taker, &maker) {
-                break;
-            }
-
-            if taker.remaining_amount <= maker.remaining_amount {
-                result.closed.insert(taker.uuid);
-            }
-            if taker.remaining_amount >= maker.remaining_amount {
-                result.closed.insert(maker.uuid);
-            }
-
-            let fill = Fill {
-                base_amount: cmp::min(taker.remaining_amount, maker.remaining_amount),
-                price: maker.price,
-                maker_uuid: maker.uuid,
-                taker_uuid: taker.uuid,
-            };
-
-            taker.remaining_amount -= fill.base_amount;
-            maker.remaining_amount -= fill.base_amount;
-
-            result.fills.push(fill); //now 'fill' belongs to 'result'
-        }
-        if let TimeInForce::IOC = taker.tif {
-            result.closed.insert(taker.uuid);
-        }
-
-        result
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
 
-    fn resting(&mut self, side: Side) -> &mut BTreeMap<PriceTime, Order> {
-        match side {
-            Side::Buy => &mut self.buy,
-            Side::Sell => &mut self.sell,
-        }
-    }
-    fn new() -> Engine {
-        Engine {
-            buy: BTreeMap::new(),
-            sell: BTreeMap::new(),
-            last_tick: 0,
-            uuid_to_side_price_time: HashMap::new(),
-            expiry_uuid: BTreeSet::new(),
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}", record.level(), record.args());
         }
     }
 
-    fn insert(&mut self, order: Order) {
-        /*
-            sort by price/time for SELL
-            sort by (-price)/time for BUY
-        */
-        if let Some(_uuid) = self.uuid_to_side_price_time.insert(
-            order.uuid,
-            SidePriceTime(order.side, order.price, order.created),
-        ) {
-            panic!("Duplicate UUID: {}", order.uuid);
-        }
-
-        // self.expiry_to_uuid.insert(order.expiry(), order.uuid);
-        self.expiry_uuid.insert(
-            TimeUuid(order.expiry(), order.uuid)
-        );
-        
-
-        match order.side {
-            Side::Buy => self
-                .buy
-                .insert(PriceTime(-order.price, order.created), order),
-            Side::Sell => self
-                .sell
-                .insert(PriceTime(order.price, order.created), order),
-        };
-    }
+    fn flush(&self) {}
+}
 
-    fn place(&mut self, command: Place, now: u64) -> MatchResult {
-        let mut order: Order = Order::create(command, now);
-        let result: MatchResult = self._match(&mut order);
+static LOGGER: StderrLogger = StderrLogger;
 
-        // Remove any closed orders from memory
-        for uuid in &result.closed {
-            self.remove(*uuid);
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
         }
-
-        //add order to resting book if not immediately closed
-        if !result.closed.contains(&order.uuid) {
-            self.insert(order);
+    };
+    if let Some(level) = options.log_level {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(level);
         }
-        result
     }
 
-    fn cancel(&mut self, uuid: Uuid) -> BTreeSet<Uuid> {
-        if self.remove(uuid) {
-            BTreeSet::from([uuid])
-        } else {
-            BTreeSet::new()
+    let mut engine = Engine::new();
+    engine.set_report_book_deltas(options.book_deltas);
+    engine.set_jitter_seed(options.jitter_seed);
+    engine.set_heartbeat_timeout(
+        options
+            .heartbeat_timeout
+            .map(|timeout| timeout.saturating_mul(options.time_unit.nanos())),
+    );
+    engine.set_reject_unknown_cancels(options.reject_unknown_cancels);
+    if let Some(path) = &options.log {
+        engine = match open_log(engine, path, options.recover) {
+            Ok(engine) => engine,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            }
+        };
+    }
+    #[cfg(feature = "server")]
+    if let Some(address) = &options.listen {
+        if let Err(e) = server::serve(address, &mut engine, &options) {
+            eprintln!("{}: {}", address, e);
+            process::exit(1);
         }
+        return;
     }
 
-    fn remove(&mut self, uuid: Uuid) -> bool {
-        /*
-            Remove from uuid_to_side_price_time, get (side, price, time)
-            Remove from self.buy/self.sell using (price,time)
-            Remove from expiry_to_uuid using order.expiry
-        */
-        let result = self.uuid_to_side_price_time.remove(&uuid);
-
-        if let Some(SidePriceTime(side, price, time)) = result {
-            let r = match side {
-                Side::Buy => self.buy.remove(&PriceTime(-price, time)),
-                Side::Sell => self.sell.remove(&PriceTime(price, time)),
-            };
-            if let Some(order) = r {
-                let expiry = order.expiry();
-                if !self.expiry_uuid.remove(&TimeUuid(expiry, uuid)){
-                    panic!("expiry/uuid missing in expiry_uuid")
-                }
-                true
-            } else {
-                panic!("Data structure mismatch")
+    // every file is opened up front, so a bad path doesn't stop a run halfway
+    let mut inputs: Vec<(&str, Box<dyn BufRead>)> = Vec::new();
+    for path in &options.paths {
+        match File::open(path) {
+            Ok(file) => inputs.push((path, Box::new(BufReader::new(file)))),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
             }
-        } else {
-            false
         }
     }
-    fn flush(&mut self, now: &u64) -> BTreeSet<Uuid> {
-        let mut expired: BTreeSet<Uuid> = BTreeSet::new();
-
-        for key in &self.expiry_uuid{
-            // key is expiry/uuid tuple struct
-        
-            if key.0 <= *now {
-                expired.insert(key.1);
-            } else {
-                break;
-            }
-        }
-
-        for uuid in &expired {
-            self.remove(*uuid);
-        }
-        expired
+    if inputs.is_empty() {
+        inputs.push(("stdin", Box::new(io::stdin().lock())));
     }
 
-    fn call(&mut self, command_at_time: CommandAtTime) -> MatchResult {
-        /*
-            I think we should always flush before a place or a cancel
-        */
-        let now = command_at_time.now;
-        let command = command_at_time.command;
-
-        if now <= self.last_tick {
-            panic!(
-                "current_tick:{} must be greater than last_tick:{}",
-                now, self.last_tick
-            );
-        }
-        self.last_tick = now;
-        let result = match command {
-            Command::Place(place) => {
-                let flushed = self.flush(&now);
-                let result = self.place(place, now);
-                merge(result, flushed)
-            }
-            Command::Cancel(uuid) => {
-                let flushed = self.flush(&now);
-                let result = MatchResult {
-                    fills: Vec::new(),
-                    closed: self.cancel(uuid),
-                };
-                merge(result, flushed)
-            }
-            Command::Flush() => MatchResult {
-                fills: Vec::new(),
-                closed: self.flush(&now),
-            },
-        };
-        result
-    }
-}
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut pacer = options.replay_speed.map(Pacer::new);
+    for (name, input) in inputs {
+        for line in input.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("{}: {}", name, e);
+                    process::exit(1);
+                }
+            };
+            process_line(&mut engine, &line, &options, pacer.as_mut(), &mut out).unwrap();
 
-/*
-Input/Output
-*/
+            /*
 
-fn time_in_force(slice: &[String]) -> TimeInForce {
-    let name: &str = &slice[0];
-    match name {
-        "IOC" => TimeInForce::IOC,
-        "GTC" => TimeInForce::GTC,
-        "GTD" => {
-            if let Some(lifetime_s) = slice.get(1) {
-                /*lifetime probably has to be >0*/
-                let lifetime = u64::from_str(lifetime_s).unwrap();
-                if lifetime < 1 {
-                    panic!("lifetime must be greater than zero")
-                }
-                TimeInForce::GTD(lifetime)
-            } else {
-                panic!("Can't parse TIF: {}", name)
-            }
-        }
-        _ => panic!("Can't parse TIF: {}", name),
-    }
-}
+                without engine.call / print_result:
+                2.092 seconds
 
-fn limit_order_command(slice: &[String]) -> Command {
-    Command::Place(Place::LimitOrder {
-        uuid: Uuid::from_str(&slice[0]).unwrap(),
-        side: Side::from_str(&slice[1]).unwrap(),
-        amount: Decimal::from_str(&slice[2]).unwrap(),
-        price: Decimal::from_str(&slice[3]).unwrap(),
-        tif: time_in_force(&slice[4..]),
-    })
-}
-fn market_order_command(slice: &[String]) -> Command {
-    Command::Place(Place::MarketOrder {
-        uuid: Uuid::from_str(&slice[0]).unwrap(),
-        side: Side::from_str(&slice[1]).unwrap(),
-        amount: Decimal::from_str(&slice[2]).unwrap(),
-    })
-}
-fn cancel_command(slice: &[String]) -> Command {
-    if let Some(uuid_s) = slice.get(0) {
-        Command::Cancel(Uuid::from_str(uuid_s).unwrap())
-    } else {
-        panic!("Can't parse cancel command")
-    }
-}
-fn parse_line(line: String) -> CommandAtTime {
-    /*Might be faster to avoid collect*/
-    let v: Vec<String> = line.split(",").map(|s| s.to_string()).collect();
-
-    let now: u64 = (&v[0]).parse().unwrap();
-    let name: &str = &v[1];
-
-    let command = match name {
-        "flush" => Command::Flush(),
-        "limit" => limit_order_command(&v[2..]),
-        "market" => market_order_command(&v[2..]),
-        "cancel" => cancel_command(&v[2..]),
-        _ => panic!("Can't parse: {}", name),
-    };
+                without print_result:
 
-    CommandAtTime {
-        now: now,
-        command: command,
-    }
-}
+                2.95 seconds
 
-fn print_result(result: &MatchResult, now: u64) {
-    /*
-    */
-    for fill in &result.fills {
-        println!(
-            "< {},fill,{},{},{},{}",
-            now, fill.maker_uuid, fill.taker_uuid, fill.base_amount, fill.price
-        );
-    }
-    /*Would be good to sort this to get identical output to Python*/
-    for uuid in &result.closed {
-        println!("< {},closed,{}", now, uuid);
-    }
-}
+                With: 6.16
 
-fn main() {
-    let mut engine = Engine::new();
-    let stdin = io::stdin();
-
-    for line in stdin.lock().lines().map(|line| line.unwrap()) {
-        println!("> {}", line);
-        let command_at_time = parse_line(line);
-        let now = command_at_time.now;
-        let result = engine.call(command_at_time);
-        print_result(&result, now);
-        //println!("no_output");
-        
-        
-        /*
-        
-            without engine.call / print_result: 
-            2.092 seconds 
-        
-            without print_result: 
-            
-            2.95 seconds
-        
-            With: 6.16 
-            
-            print a fixed string: 4.2
-            
-        
-        */
+                print a fixed string: 4.2
+
+
+            */
+        }
     }
 }
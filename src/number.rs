@@ -0,0 +1,111 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Rem, Sub, SubAssign};
+use std::str::FromStr;
+
+use rust_decimal::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::QuoteRounding;
+
+/*
+    The arithmetic the engine needs on prices and amounts. Decimal is the
+    default everywhere. An integer type such as i64 works too, for prices
+    counted in ticks and amounts in lots: it is much cheaper, but division
+    truncates, so fees and pro-rata shares are rounded towards zero, and
+    it is up to the caller to keep price * amount from overflowing.
+*/
+pub trait Number:
+    Copy
+    + Ord
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + FromStr
+    + From<u32>
+    + Serialize
+    + DeserializeOwned
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Rem<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Sum
+    + for<'a> Sum<&'a Self>
+{
+    const ZERO: Self;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    fn abs(self) -> Self {
+        if self < Self::ZERO {
+            -self
+        } else {
+            self
+        }
+    }
+
+    // number of decimal places
+    fn scale(&self) -> u32;
+
+    // cut back to `scale` decimal places, towards zero
+    fn truncate_to(self, scale: u32) -> Self;
+
+    // round to `precision` decimal places
+    fn round_to(self, precision: u32, rounding: QuoteRounding) -> Self;
+
+    /*
+        The same value in one canonical form, so that equal prices also
+        print the same: 100.00 becomes 100
+    */
+    fn normalize(self) -> Self {
+        self
+    }
+}
+
+impl Number for Decimal {
+    const ZERO: Self = Decimal::ZERO;
+
+    fn scale(&self) -> u32 {
+        Decimal::scale(self)
+    }
+
+    fn truncate_to(self, scale: u32) -> Self {
+        self.round_dp_with_strategy(scale, RoundingStrategy::ToZero)
+    }
+
+    fn round_to(self, precision: u32, rounding: QuoteRounding) -> Self {
+        let strategy = match rounding {
+            QuoteRounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            QuoteRounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        };
+        self.round_dp_with_strategy(precision, strategy)
+    }
+
+    fn normalize(self) -> Self {
+        Decimal::normalize(&self)
+    }
+}
+
+// whole ticks or lots, so there are never any decimal places to round
+impl Number for i64 {
+    const ZERO: Self = 0;
+
+    fn scale(&self) -> u32 {
+        0
+    }
+
+    fn truncate_to(self, _scale: u32) -> Self {
+        self
+    }
+
+    fn round_to(self, _precision: u32, _rounding: QuoteRounding) -> Self {
+        self
+    }
+}
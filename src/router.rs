@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    parse_line_in, CommandAtTime, Engine, EngineError, MatchResult, Number, ParseError, TimeUnit,
+};
+
+/*
+    Many single-instrument engines behind one entry point, each keyed by
+    its symbol. Every book is an ordinary Engine with its own config.
+
+    The router keeps one clock for all of them: a command older than the
+    last one routed, to whatever symbol, is refused with
+    EngineError::OutOfOrder, as a single engine would. Each book only moves
+    its own clock when it is sent something, so orders expire in a book
+    when it is next called, a Flush() included, not when another one is.
+*/
+pub struct EngineRouter<N: Number = Decimal> {
+    engines: HashMap<String, Engine<N>>,
+    last_tick: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(bound = "N: Number")]
+pub struct RoutedResult<N = Decimal> {
+    pub symbol: String,
+    #[serde(flatten)]
+    pub result: MatchResult<N>,
+}
+
+impl<N: Number> Default for EngineRouter<N> {
+    fn default() -> Self {
+        EngineRouter::new()
+    }
+}
+
+impl<N: Number> EngineRouter<N> {
+    pub fn new() -> EngineRouter<N> {
+        EngineRouter {
+            engines: HashMap::new(),
+            last_tick: 0,
+        }
+    }
+
+    // hands back whatever engine was there before
+    pub fn add(&mut self, symbol: &str, engine: Engine<N>) -> Option<Engine<N>> {
+        self.engines.insert(symbol.to_string(), engine)
+    }
+
+    pub fn remove(&mut self, symbol: &str) -> Option<Engine<N>> {
+        self.engines.remove(symbol)
+    }
+
+    pub fn engine(&self, symbol: &str) -> Option<&Engine<N>> {
+        self.engines.get(symbol)
+    }
+
+    pub fn engine_mut(&mut self, symbol: &str) -> Option<&mut Engine<N>> {
+        self.engines.get_mut(symbol)
+    }
+
+    // sorted, so the order doesn't depend on the hash map
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut symbols: Vec<&str> = self.engines.keys().map(String::as_str).collect();
+        symbols.sort();
+        symbols
+    }
+
+    pub fn call(
+        &mut self,
+        symbol: &str,
+        command_at_time: CommandAtTime<N>,
+    ) -> Result<RoutedResult<N>, EngineError> {
+        if command_at_time.now < self.last_tick {
+            return Err(EngineError::OutOfOrder {
+                now: command_at_time.now,
+                last_tick: self.last_tick,
+            });
+        }
+        let engine = match self.engines.get_mut(symbol) {
+            Some(engine) => engine,
+            None => return Err(EngineError::UnknownSymbol(symbol.to_string())),
+        };
+        let now = command_at_time.now;
+        let result = engine.call(command_at_time)?;
+        self.last_tick = now;
+        Ok(RoutedResult {
+            symbol: symbol.to_string(),
+            result,
+        })
+    }
+}
+
+/*
+    A CSV line for a router: the symbol, then a line as parse_line_in takes
+    it, e.g. BTC-USD,5,cancel,<uuid>. Errors point into the whole line.
+*/
+pub fn parse_routed_line<N: Number>(
+    line: String,
+    unit: TimeUnit,
+) -> Result<(String, CommandAtTime<N>), ParseError> {
+    let (symbol, rest) = match line.split_once(',') {
+        Some((symbol, rest)) if !symbol.is_empty() => (symbol, rest),
+        _ => return Err(ParseError::MissingField { line, field: 0 }),
+    };
+    match parse_line_in(rest.to_string(), unit) {
+        Ok(command_at_time) => Ok((symbol.to_string(), command_at_time)),
+        Err(e) => Err(e.shifted(&line, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASK: &str = "00000000-0000-0000-0000-000000000001";
+    const BID: &str = "00000000-0000-0000-0000-000000000002";
+
+    fn parse(line: &str) -> Result<(String, CommandAtTime), ParseError> {
+        parse_routed_line(line.to_string(), TimeUnit::Nanoseconds)
+    }
+
+    fn call(router: &mut EngineRouter, line: &str) -> Result<RoutedResult, EngineError> {
+        let (symbol, command_at_time) = parse(line).unwrap();
+        router.call(&symbol, command_at_time)
+    }
+
+    #[test]
+    fn books_are_separate_but_share_one_clock() {
+        let mut router = EngineRouter::new();
+        router.add("BTC", Engine::new());
+        router.add("ETH", Engine::new());
+
+        call(
+            &mut router,
+            &format!("BTC,5,limit,{},1,sell,1,100,GTC", ASK),
+        )
+        .unwrap();
+        // the same price on another symbol doesn't cross
+        let routed = call(&mut router, &format!("ETH,6,limit,{},2,buy,1,100,GTC", BID)).unwrap();
+        assert_eq!(routed.symbol, "ETH");
+        assert!(routed.result.fills.is_empty());
+
+        // BTC was last called at 5, but the router has seen 6
+        let late = call(&mut router, &format!("BTC,5,cancel,{}", ASK));
+        assert_eq!(
+            late.unwrap_err(),
+            EngineError::OutOfOrder {
+                now: 5,
+                last_tick: 6
+            }
+        );
+        assert!(router
+            .engine("BTC")
+            .unwrap()
+            .get_order(ASK.parse().unwrap())
+            .is_some());
+
+        let unknown = call(&mut router, &format!("SOL,7,cancel,{}", ASK));
+        assert_eq!(
+            unknown.unwrap_err(),
+            EngineError::UnknownSymbol("SOL".to_string())
+        );
+        // nor does a refused command move the clock
+        call(&mut router, &format!("BTC,6,cancel,{}", ASK)).unwrap();
+        assert_eq!(router.symbols(), ["BTC", "ETH"]);
+    }
+
+    #[test]
+    fn parse_errors_count_fields_from_the_symbol() {
+        let line = format!("BTC,5,limit,{},1,sideways,1,100,GTC", ASK);
+        assert_eq!(
+            parse(&line).unwrap_err(),
+            ParseError::UnknownSide { line, field: 5 }
+        );
+        let line = "BTC,5,cancel,not-a-uuid".to_string();
+        assert_eq!(
+            parse(&line).unwrap_err(),
+            ParseError::BadUuid { line, field: 3 }
+        );
+        for line in ["", ",5,cancel", "BTC"] {
+            assert_eq!(
+                parse(line).unwrap_err(),
+                ParseError::MissingField {
+                    line: line.to_string(),
+                    field: 0
+                }
+            );
+        }
+    }
+}
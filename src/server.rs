@@ -0,0 +1,34 @@
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+
+use matchbox::Engine;
+
+use crate::{process_line, Options};
+
+/*
+    Serve one engine over TCP. A client sends command lines exactly as they
+    would be piped to stdin and reads back the same output, written as each
+    line is applied. Connections are taken one at a time, in the order they
+    arrive, and all of them drive the same engine.
+*/
+pub fn serve(address: &str, engine: &mut Engine, options: &Options) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    for stream in listener.incoming() {
+        // a client going away doesn't stop the server
+        if let Err(e) = stream.and_then(|stream| session(stream, engine, options)) {
+            eprintln!("connection: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn session(stream: TcpStream, engine: &mut Engine, options: &Options) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    for line in reader.lines() {
+        process_line(engine, &line?, options, None, &mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
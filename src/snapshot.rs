@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    Engine, EngineConfig, Fill, MatchingPolicy, MidRounding, Number, Order, Position, PriceTime,
+    QuoteRounding, ReplaceMissing, SelfTradePrevention, Side, SidePriceTime, StopOrder, TieBreak,
+    TimeUuid,
+};
+
+/*
+    Everything needed to rebuild an Engine exactly, including each order's
+    position in its queue, so matching carries on as if there had been no
+    restart. Serialize it with any serde format to persist it.
+*/
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound = "N: Number")]
+pub struct EngineSnapshot<N = Decimal> {
+    last_tick: u64,
+    sequence: u64,
+    event_sequence: u64,
+    last_trade_price: Option<N>,
+    fill_count: u64,
+    base_volume: N,
+    quote_volume: N,
+    recent_trades: Vec<Fill<N>>,
+    recent_trades_capacity: usize,
+    quote_precision: Option<(u32, QuoteRounding)>,
+    halted: bool,
+    auction: bool,
+    reference_band: Option<N>,
+    report_price_improvement: bool,
+    allow_negative_prices: bool,
+    max_resting_orders: Option<usize>,
+    replace_missing: ReplaceMissing,
+    report_book_deltas: bool,
+    rng: Option<u64>, // the jitter generator's state, not its original seed
+    heartbeat_timeout: Option<u64>,
+    heartbeats: BTreeMap<u64, u64>,
+    reject_unknown_cancels: bool,
+    min_fill_ratio: Option<N>,
+    mid_rounding: MidRounding,
+    positions: BTreeMap<u64, Position<N>>,
+    self_trade_prevention: SelfTradePrevention,
+    matching_policy: MatchingPolicy,
+    tie_break: TieBreak,
+    gtc_lifetime: Option<u64>,
+    max_gtd_lifetime: Option<u64>,
+    price_tick: N,
+    lot_size: N,
+    maker_bps: N,
+    taker_bps: N,
+    orders: Vec<Queued<Order<N>>>, // buys then sells, each in priority order
+    stops: Vec<Queued<StopOrder<N>>>,
+    expiry_index: Vec<(u64, u64, Uuid)>, // expiry, queue sequence, uuid
+}
+
+/*
+    `time` and `sequence` are the order's queue position, which for a
+    refilled iceberg slice or a triggered stop differs from `created`
+*/
+#[derive(Clone, Serialize, Deserialize)]
+struct Queued<T> {
+    time: u64,
+    sequence: u64,
+    item: T,
+}
+
+fn queued<N, T: Clone>(key: &PriceTime<N>, item: &T) -> Queued<T> {
+    Queued {
+        time: key.1,
+        sequence: key.2,
+        item: item.clone(),
+    }
+}
+
+// like Engine::new, restore is for Decimal so a deserialized snapshot needs no annotation
+impl Engine {
+    pub fn restore(snapshot: EngineSnapshot) -> Engine {
+        Engine::from_snapshot(snapshot)
+    }
+}
+
+impl<N: Number> Engine<N> {
+    pub fn snapshot(&self) -> EngineSnapshot<N> {
+        EngineSnapshot {
+            last_tick: self.last_tick,
+            sequence: self.sequence,
+            event_sequence: self.event_sequence,
+            last_trade_price: self.last_trade_price,
+            fill_count: self.fill_count,
+            base_volume: self.base_volume,
+            quote_volume: self.quote_volume,
+            recent_trades: self.recent_trades.iter().cloned().collect(),
+            recent_trades_capacity: self.recent_trades_capacity,
+            quote_precision: self.quote_precision,
+            halted: self.halted,
+            auction: self.auction,
+            reference_band: self.reference_band,
+            report_price_improvement: self.report_price_improvement,
+            allow_negative_prices: self.allow_negative_prices,
+            max_resting_orders: self.max_resting_orders,
+            replace_missing: self.replace_missing,
+            report_book_deltas: self.report_book_deltas,
+            rng: self.rng.map(|rng| rng.state()),
+            heartbeat_timeout: self.heartbeat_timeout,
+            heartbeats: self.heartbeats.iter().map(|(k, v)| (*k, *v)).collect(),
+            reject_unknown_cancels: self.reject_unknown_cancels,
+            min_fill_ratio: self.min_fill_ratio,
+            mid_rounding: self.mid_rounding,
+            positions: self.positions.iter().map(|(k, v)| (*k, *v)).collect(),
+            self_trade_prevention: self.self_trade_prevention,
+            matching_policy: self.matching_policy,
+            tie_break: self.tie_break,
+            gtc_lifetime: self.gtc_lifetime,
+            max_gtd_lifetime: self.max_gtd_lifetime,
+            price_tick: self.price_tick,
+            lot_size: self.lot_size,
+            maker_bps: self.maker_bps,
+            taker_bps: self.taker_bps,
+            orders: self
+                .buy
+                .iter()
+                .chain(self.sell.iter())
+                .map(|(key, order)| queued(key, order))
+                .collect(),
+            stops: self
+                .buy_stops
+                .iter()
+                .chain(self.sell_stops.iter())
+                .map(|(key, stop)| queued(key, stop))
+                .collect(),
+            expiry_index: self
+                .expiry_uuid
+                .iter()
+                .map(|TimeUuid(expiry, sequence, uuid)| (*expiry, *sequence, *uuid))
+                .collect(),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: EngineSnapshot<N>) -> Engine<N> {
+        let mut engine = Engine::with_config(EngineConfig {
+            matching_policy: snapshot.matching_policy,
+            tie_break: snapshot.tie_break,
+            self_trade_prevention: snapshot.self_trade_prevention,
+            maker_bps: snapshot.maker_bps,
+            taker_bps: snapshot.taker_bps,
+            price_tick: snapshot.price_tick,
+            lot_size: snapshot.lot_size,
+            gtc_lifetime: snapshot.gtc_lifetime,
+            max_gtd_lifetime: snapshot.max_gtd_lifetime,
+            quote_precision: snapshot.quote_precision,
+            recent_trades_capacity: snapshot.recent_trades_capacity,
+            reference_band: snapshot.reference_band,
+            report_price_improvement: snapshot.report_price_improvement,
+            allow_negative_prices: snapshot.allow_negative_prices,
+            max_resting_orders: snapshot.max_resting_orders,
+            replace_missing: snapshot.replace_missing,
+            report_book_deltas: snapshot.report_book_deltas,
+            jitter_seed: snapshot.rng,
+            heartbeat_timeout: snapshot.heartbeat_timeout,
+            reject_unknown_cancels: snapshot.reject_unknown_cancels,
+            min_fill_ratio: snapshot.min_fill_ratio,
+            mid_rounding: snapshot.mid_rounding,
+        });
+        engine.last_tick = snapshot.last_tick;
+        engine.sequence = snapshot.sequence;
+        engine.event_sequence = snapshot.event_sequence;
+        engine.last_trade_price = snapshot.last_trade_price;
+        engine.fill_count = snapshot.fill_count;
+        engine.base_volume = snapshot.base_volume;
+        engine.quote_volume = snapshot.quote_volume;
+        engine.recent_trades = snapshot.recent_trades.into();
+        engine.halted = snapshot.halted;
+        engine.auction = snapshot.auction;
+        engine.positions = snapshot.positions.into_iter().collect();
+        engine.heartbeats = snapshot.heartbeats.into_iter().collect();
+
+        for Queued {
+            time,
+            sequence,
+            item: order,
+        } in snapshot.orders
+        {
+            let price = order.resting_price();
+            engine.index_account(order.account, order.uuid);
+            if order.peg.is_some() {
+                engine.pegged.insert(order.uuid);
+            }
+            engine
+                .uuid_to_side_price_time
+                .insert(order.uuid, SidePriceTime(order.side, price, time, sequence));
+            match order.side {
+                Side::Buy => engine.buy.insert(PriceTime(-price, time, sequence), order),
+                Side::Sell => engine.sell.insert(PriceTime(price, time, sequence), order),
+            };
+        }
+        engine.best_buy = engine.buy.keys().next().copied();
+        engine.best_sell = engine.sell.keys().next().copied();
+
+        for Queued {
+            time,
+            sequence,
+            item: stop,
+        } in snapshot.stops
+        {
+            let (side, stop_price) = (stop.order.side, stop.stop_price);
+            engine.index_account(stop.order.account, stop.order.uuid);
+            engine.uuid_to_stop.insert(
+                stop.order.uuid,
+                SidePriceTime(side, stop_price, time, sequence),
+            );
+            match side {
+                Side::Buy => engine
+                    .buy_stops
+                    .insert(PriceTime(stop_price, time, sequence), stop),
+                Side::Sell => engine
+                    .sell_stops
+                    .insert(PriceTime(-stop_price, time, sequence), stop),
+            };
+        }
+
+        engine.expiry_uuid = snapshot
+            .expiry_index
+            .into_iter()
+            .map(|(expiry, sequence, uuid)| TimeUuid(expiry, sequence, uuid))
+            .collect::<BTreeSet<TimeUuid>>();
+        // a restored engine reports deltas against the book as restored
+        engine.reset_levels();
+        engine.debug_assert_consistent();
+        engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_line, MatchResult};
+
+    const LINES: [&str; 8] = [
+        "1,limit,00000000-0000-0000-0000-000000000001,1,sell,1,101,GTC",
+        "2,limit,00000000-0000-0000-0000-000000000002,2,sell,2,101,GTD,5",
+        "2,limit,00000000-0000-0000-0000-000000000003,3,sell,1,100,GTC",
+        "3,limit,00000000-0000-0000-0000-000000000004,4,buy,3,99,GTC",
+        // split here: everything after runs on both engines
+        "4,market,00000000-0000-0000-0000-000000000005,5,buy,2",
+        "5,limit,00000000-0000-0000-0000-000000000006,6,buy,1,99,GTC",
+        "6,limit,00000000-0000-0000-0000-000000000007,7,buy,3,101,GTC",
+        "8,market,00000000-0000-0000-0000-000000000008,8,sell,10",
+    ];
+
+    fn run(engine: &mut Engine, lines: &[&str]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| {
+                let result: MatchResult =
+                    engine.call(parse_line(line.to_string()).unwrap()).unwrap();
+                serde_json::to_string(&result).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restored_engine_matches_as_if_never_restarted() {
+        let (before, after) = LINES.split_at(4);
+        let mut engine = Engine::new();
+        run(&mut engine, before);
+        let bytes = serde_json::to_vec(&engine.snapshot()).unwrap();
+        let expected = run(&mut engine, after);
+
+        let snapshot: EngineSnapshot = serde_json::from_slice(&bytes).unwrap();
+        let mut restored = Engine::restore(snapshot);
+        assert_eq!(restored.check_consistency(), Ok(()));
+        assert_eq!(run(&mut restored, after), expected);
+        // the same again, queue positions and all
+        assert_eq!(
+            serde_json::to_string(&restored.snapshot()).unwrap(),
+            serde_json::to_string(&engine.snapshot()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs;
+use std::process::Command;
+
+/*
+    Runs the binary over a recorded input and compares what it prints with
+    the recorded output, byte for byte. After a deliberate change to the
+    output, regenerate the .out file and review the diff. Hands back what
+    went to stderr.
+*/
+fn check(input: &str, args: &[&str], expected: &str) -> String {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/");
+    let output = Command::new(env!("CARGO_BIN_EXE_matchbox"))
+        .args(args)
+        .arg(format!("{}{}", dir, input))
+        .output()
+        .expect("couldn't run matchbox");
+    assert!(output.status.success(), "matchbox failed on {}", input);
+    let expected = fs::read_to_string(format!("{}{}", dir, expected)).unwrap();
+    let actual = String::from_utf8(output.stdout).unwrap();
+    for (line, (actual, expected)) in actual.lines().zip(expected.lines()).enumerate() {
+        assert_eq!(actual, expected, "{} differs at line {}", input, line + 1);
+    }
+    assert_eq!(actual.lines().count(), expected.lines().count());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn legacy_output_is_in_canonical_order() {
+    check("legacy.csv", &[], "legacy.out");
+}
+
+#[test]
+fn json_output() {
+    check("legacy.csv", &["--format", "json"], "json.out");
+}
+
+#[test]
+fn fix_output_with_level_changes() {
+    check(
+        "fix.csv",
+        &["--format", "fix", "--book-deltas", "--symbol", "BTC-USD"],
+        "fix.out",
+    );
+}
+
+#[test]
+fn blank_lines_are_skipped_and_bad_ones_reported() {
+    let stderr = check("blank_lines.csv", &[], "blank_lines.out");
+    assert_eq!(
+        stderr,
+        "skipping line: bad uuid at field 2: 2,limit,not-a-uuid,2,buy,2,100,GTC\n"
+    );
+}